@@ -0,0 +1,339 @@
+use crate::message_store::{now_timestamp, SqliteMessageStore};
+use rusqlite::{params, Connection, Result};
+use std::sync::{Arc, Mutex};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExecutionKind {
+    Python,
+    ToolCall,
+}
+
+impl ExecutionKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ExecutionKind::Python => "python",
+            ExecutionKind::ToolCall => "tool_call",
+        }
+    }
+
+    pub fn parse(kind: &str) -> Self {
+        match kind {
+            "python" => ExecutionKind::Python,
+            _ => ExecutionKind::ToolCall,
+        }
+    }
+}
+
+// One run of code or of a tool. `input` is the code, or the call arguments as JSON.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExecutionRecord {
+    // assigned by the log, ignored when recording
+    pub id: i64,
+    pub conversation_id: Option<String>,
+    pub agent_name: String,
+    pub kind: ExecutionKind,
+    pub name: String,
+    pub input: String,
+    pub stdout: String,
+    pub stderr: String,
+    pub result: String,
+    pub duration_ms: i64,
+    pub success: bool,
+    pub created_at: i64,
+}
+
+impl ExecutionRecord {
+    pub fn new(agent_name: &str, kind: ExecutionKind, name: &str, input: &str) -> Self {
+        ExecutionRecord {
+            id: 0,
+            conversation_id: None,
+            agent_name: agent_name.to_string(),
+            kind,
+            name: name.to_string(),
+            input: input.to_string(),
+            stdout: String::new(),
+            stderr: String::new(),
+            result: String::new(),
+            duration_ms: 0,
+            success: false,
+            created_at: now_timestamp(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct AuditQuery {
+    pub conversation_id: Option<String>,
+    pub agent_name: Option<String>,
+    pub kind: Option<ExecutionKind>,
+    pub success: Option<bool>,
+    // unix seconds, inclusive
+    pub since: Option<i64>,
+    // newest first; 0 means no limit
+    pub limit: usize,
+}
+
+impl AuditQuery {
+    fn matches(&self, record: &ExecutionRecord) -> bool {
+        self.conversation_id
+            .as_ref()
+            .is_none_or(|id| record.conversation_id.as_ref() == Some(id))
+            && self
+                .agent_name
+                .as_ref()
+                .is_none_or(|name| &record.agent_name == name)
+            && self.kind.is_none_or(|kind| record.kind == kind)
+            && self.success.is_none_or(|success| record.success == success)
+            && self.since.is_none_or(|since| record.created_at >= since)
+    }
+}
+
+pub fn record_execution(conn: &Connection, record: &ExecutionRecord) -> Result<i64> {
+    conn.execute(
+        "INSERT INTO ExecutionAudit (conversation_id, agent_name, kind, name, input, stdout, stderr, result, duration_ms, success, created_at)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+        params![
+            record.conversation_id,
+            record.agent_name,
+            record.kind.as_str(),
+            record.name,
+            record.input,
+            record.stdout,
+            record.stderr,
+            record.result,
+            record.duration_ms,
+            record.success,
+            record.created_at
+        ],
+    )?;
+    Ok(conn.last_insert_rowid())
+}
+
+pub fn query_executions(conn: &Connection, query: &AuditQuery) -> Result<Vec<ExecutionRecord>> {
+    let mut stmt = conn.prepare(
+        "SELECT id, conversation_id, agent_name, kind, name, input, stdout, stderr, result, duration_ms, success, created_at
+        FROM ExecutionAudit
+        WHERE (?1 IS NULL OR conversation_id = ?1)
+            AND (?2 IS NULL OR agent_name = ?2)
+            AND (?3 IS NULL OR kind = ?3)
+            AND (?4 IS NULL OR success = ?4)
+            AND (?5 IS NULL OR created_at >= ?5)
+        ORDER BY id DESC
+        LIMIT ?6",
+    )?;
+    let limit = match query.limit {
+        0 => -1,
+        limit => limit as i64,
+    };
+    let rows = stmt.query_map(
+        params![
+            query.conversation_id,
+            query.agent_name,
+            query.kind.map(|kind| kind.as_str()),
+            query.success,
+            query.since,
+            limit
+        ],
+        |row| {
+            Ok(ExecutionRecord {
+                id: row.get(0)?,
+                conversation_id: row.get(1)?,
+                agent_name: row.get(2)?,
+                kind: ExecutionKind::parse(&row.get::<_, String>(3)?),
+                name: row.get(4)?,
+                input: row.get(5)?,
+                stdout: row.get(6)?,
+                stderr: row.get(7)?,
+                result: row.get(8)?,
+                duration_ms: row.get(9)?,
+                success: row.get(10)?,
+                created_at: row.get(11)?,
+            })
+        },
+    )?;
+    rows.collect()
+}
+
+pub trait AuditLog: Send + Sync {
+    fn record(&self, record: ExecutionRecord) -> anyhow::Result<i64>;
+
+    fn query(&self, query: &AuditQuery) -> anyhow::Result<Vec<ExecutionRecord>>;
+}
+
+// The ExecutionAudit table lives in the message database, created by its migrations.
+impl AuditLog for SqliteMessageStore {
+    fn record(&self, record: ExecutionRecord) -> anyhow::Result<i64> {
+        self.with_connection(|conn| record_execution(conn, &record))
+    }
+
+    fn query(&self, query: &AuditQuery) -> anyhow::Result<Vec<ExecutionRecord>> {
+        self.with_connection(|conn| query_executions(conn, query))
+    }
+}
+
+#[derive(Default)]
+pub struct InMemoryAuditLog {
+    records: Mutex<Vec<ExecutionRecord>>,
+}
+
+impl InMemoryAuditLog {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl AuditLog for InMemoryAuditLog {
+    fn record(&self, mut record: ExecutionRecord) -> anyhow::Result<i64> {
+        let mut records = self.records.lock().unwrap();
+        record.id = records.len() as i64 + 1;
+        records.push(record);
+        Ok(records.len() as i64)
+    }
+
+    fn query(&self, query: &AuditQuery) -> anyhow::Result<Vec<ExecutionRecord>> {
+        let records = self.records.lock().unwrap();
+        let matching = records.iter().rev().filter(|record| query.matches(record));
+        Ok(match query.limit {
+            0 => matching.cloned().collect(),
+            limit => matching.take(limit).cloned().collect(),
+        })
+    }
+}
+
+// For async callers: the write happens on the blocking pool and failures are reported, not
+// returned; an audit problem should not stop the agent.
+pub async fn record_async(audit_log: Arc<dyn AuditLog>, record: ExecutionRecord) {
+    let outcome = tokio::task::spawn_blocking(move || audit_log.record(record)).await;
+    match outcome {
+        Ok(Ok(_)) => {}
+        Ok(Err(e)) => println!("Error writing audit record: {:?}", e),
+        Err(e) => println!("Error writing audit record: {:?}", e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conversable_agent::{ConversableAgent, Message};
+    use crate::exec_python::run_python_audited;
+    use crate::llama_structs::Content;
+    use crate::model_client::ScriptedModelClient;
+    use async_openai::types::Role;
+    use serde_json::json;
+
+    #[test]
+    fn python_runs_are_recorded_with_their_output() {
+        let store = SqliteMessageStore::open_in_memory().unwrap();
+
+        let ok = run_python_audited("print('hello')", "coder", Some("c1"), Some(&store));
+        assert_eq!(ok, Ok("hello\n".to_string()));
+        let failed = run_python_audited(
+            "import sys\nprint('partial')\nsys.stderr.write('warned')\nraise ValueError('boom')",
+            "coder",
+            Some("c1"),
+            Some(&store),
+        );
+        assert!(failed.unwrap_err().contains("boom"));
+        store
+            .record(ExecutionRecord::new(
+                "planner",
+                ExecutionKind::ToolCall,
+                "search",
+                "{}",
+            ))
+            .unwrap();
+
+        let runs = store
+            .query(&AuditQuery {
+                agent_name: Some("coder".to_string()),
+                ..AuditQuery::default()
+            })
+            .unwrap();
+        assert_eq!(runs.len(), 2);
+        assert!(!runs[0].success);
+        assert_eq!(runs[0].stdout, "partial\n");
+        assert!(runs[0].stderr.starts_with("warned"));
+        assert_eq!(runs[0].conversation_id.as_deref(), Some("c1"));
+        assert!(runs[1].success);
+        assert_eq!(runs[1].input, "print('hello')");
+        assert_eq!(runs[1].result, "hello\n");
+
+        let failures = store
+            .query(&AuditQuery {
+                success: Some(false),
+                ..AuditQuery::default()
+            })
+            .unwrap();
+        assert_eq!(failures.len(), 2);
+        let latest_tool_call = store
+            .query(&AuditQuery {
+                kind: Some(ExecutionKind::ToolCall),
+                limit: 1,
+                ..AuditQuery::default()
+            })
+            .unwrap();
+        assert_eq!(latest_tool_call[0].name, "search");
+    }
+
+    #[tokio::test]
+    async fn tool_calls_run_by_an_agent_are_recorded() {
+        let audit_log = Arc::new(InMemoryAuditLog::new());
+        let client = Arc::new(ScriptedModelClient::from_texts(&[
+            r#"<tool_call>{"name": "add", "arguments": {"a": "1", "b": "2"}}</tool_call>"#,
+            r#"<tool_call>{"name": "add", "arguments": {"a": "x"}}</tool_call>"#,
+            "The sum is 3.",
+        ]));
+        let mut agent = ConversableAgent::new("calculator");
+        agent.set_model_client(client.clone());
+        agent.set_audit_log(audit_log.clone());
+        agent
+            .register_tool(
+                json!({"name": "add", "parameters": {"type": "object", "properties": {}}}),
+                |arguments| async move {
+                    let number = |key: &str| -> anyhow::Result<i64> {
+                        Ok(arguments.get(key).map_or("", String::as_str).parse()?)
+                    };
+                    Ok((number("a")? + number("b")?).to_string())
+                },
+            )
+            .unwrap();
+
+        let question = Message::new(
+            Some(Content::Text("What is 1 + 2?".to_string())),
+            None,
+            Some(Role::User),
+        );
+        let reply = agent.a_generate_reply(vec![question], None).await.unwrap();
+        assert_eq!(
+            reply.content,
+            Some(Content::Text("The sum is 3.".to_string()))
+        );
+
+        // the model saw the result of each call
+        let last_request = client.requests().pop().unwrap();
+        assert_eq!(last_request.len(), 5);
+        assert_eq!(
+            last_request[2].content,
+            Some(Content::Text("3".to_string()))
+        );
+        assert_eq!(last_request[2].role, Some(Role::Tool));
+
+        let records = audit_log.query(&AuditQuery::default()).unwrap();
+        assert_eq!(records.len(), 2);
+        assert!(!records[0].success);
+        assert!(records[0].result.starts_with("Error running tool add"));
+        assert_eq!(records[1].kind, ExecutionKind::ToolCall);
+        assert_eq!(records[1].name, "add");
+        assert_eq!(records[1].agent_name, "calculator");
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&records[1].input).unwrap(),
+            json!({"a": "1", "b": "2"})
+        );
+        assert_eq!(records[1].result, "3");
+        assert!(records[1].success);
+        assert_eq!(
+            records[1].conversation_id.as_deref(),
+            Some(agent.conversation_id.as_str())
+        );
+    }
+}
@@ -1,17 +1,28 @@
 // use crate::exec_python::run_python;
+use crate::audit_log::{record_async, AuditLog, ExecutionKind, ExecutionRecord};
 use crate::exec_python::*;
 use crate::llama_structs::*;
 use crate::llm_llama_local::*;
+use crate::message_store::{new_conversation_id, AsyncMessageStore, MessageStore};
+use crate::model_client::{default_model_client, ModelClient, OpenAiCompatibleClient};
+use crate::vector_memory::VectorMemory;
 use crate::CODE_PYTHON_SYSTEM_MESSAGE;
 use async_openai::types::Role;
+use async_trait::async_trait;
+use futures::future::BoxFuture;
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
 use std::collections::{HashMap, VecDeque};
+use std::future::Future;
 use std::sync::{Arc, Mutex};
 
-type Context = HashMap<String, String>;
+pub type Context = HashMap<String, String>;
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+// Runs one tool: gets the call arguments, returns the text the model is given as the result.
+pub type ToolHandler =
+    Arc<dyn Fn(Context) -> BoxFuture<'static, anyhow::Result<String>> + Send + Sync>;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct Message {
     pub content: Option<Content>,
     pub name: Option<String>,
@@ -42,7 +53,8 @@ impl Message {
     }
 }
 
-pub trait Agent {
+#[async_trait]
+pub trait Agent: Send + Sync {
     fn name(&self) -> String;
 
     fn description(&self) -> String;
@@ -50,6 +62,12 @@ pub trait Agent {
     fn system_message(&self) -> String;
 
     fn set_description(&mut self, description: String);
+
+    async fn a_generate_reply(
+        &self,
+        messages: Vec<Message>,
+        sender: Option<Arc<ConversableAgent>>,
+    ) -> Option<Message>;
 }
 
 pub struct ConversableAgent {
@@ -63,6 +81,17 @@ pub struct ConversableAgent {
     pub default_auto_reply: Value,
     pub description: String,
     pub chat_messages: Option<Vec<Message>>,
+    pub store: Option<AsyncMessageStore>,
+    pub conversation_id: String,
+    pub memory: Option<Arc<VectorMemory>>,
+    pub audit_log: Option<Arc<dyn AuditLog>>,
+    pub model_client: Arc<dyn ModelClient>,
+    // definitions offered to a model with native function calling
+    pub tools: Vec<Value>,
+    // tools this agent runs itself, by name; other tool calls are handed to the caller
+    pub tool_handlers: HashMap<String, ToolHandler>,
+    // skip a response cache in front of the model client
+    pub cache_bypass: bool,
 }
 impl Clone for ConversableAgent {
     fn clone(&self) -> Self {
@@ -77,9 +106,18 @@ impl Clone for ConversableAgent {
             default_auto_reply: self.default_auto_reply.clone(),
             description: self.description.clone(),
             chat_messages: self.chat_messages.clone(),
+            store: self.store.clone(),
+            conversation_id: self.conversation_id.clone(),
+            memory: self.memory.clone(),
+            audit_log: self.audit_log.clone(),
+            model_client: self.model_client.clone(),
+            tools: self.tools.clone(),
+            tool_handlers: self.tool_handlers.clone(),
+            cache_bypass: self.cache_bypass,
         }
     }
 }
+#[async_trait]
 impl Agent for ConversableAgent {
     fn name(&self) -> String {
         self.name.clone()
@@ -96,6 +134,14 @@ impl Agent for ConversableAgent {
     fn set_description(&mut self, description: String) {
         self.description = description;
     }
+
+    async fn a_generate_reply(
+        &self,
+        messages: Vec<Message>,
+        sender: Option<Arc<ConversableAgent>>,
+    ) -> Option<Message> {
+        ConversableAgent::a_generate_reply(self, messages, sender).await
+    }
 }
 
 impl ConversableAgent {
@@ -111,6 +157,14 @@ impl ConversableAgent {
             default_auto_reply: json!("this is user_proxy"),
             description: String::from("agent acting as user_proxy"),
             chat_messages: Some(vec![]),
+            store: None,
+            conversation_id: new_conversation_id(),
+            memory: None,
+            audit_log: None,
+            model_client: default_model_client(),
+            tools: Vec::new(),
+            tool_handlers: HashMap::new(),
+            cache_bypass: false,
         }
     }
     pub async fn send(
@@ -121,6 +175,19 @@ impl ConversableAgent {
         request_reply: Option<bool>,
     ) {
         let agent_id = recipient.lock().unwrap().name.clone();
+        if let Some(persistent) = &self.store {
+            if let Err(e) = persistent
+                .save_message(
+                    &self.conversation_id,
+                    self.name.clone(),
+                    message.clone(),
+                    agent_id.clone(),
+                )
+                .await
+            {
+                println!("Error saving message of {}: {:?}", self.name, e);
+            }
+        }
         let mut store = message_store.lock().unwrap();
         let queue = store.entry(agent_id).or_insert_with(VecDeque::new);
         queue.push_back(message);
@@ -143,15 +210,61 @@ impl ConversableAgent {
         sender: Option<Arc<ConversableAgent>>,
     ) -> Option<Message> {
         let max_token = 1000u16;
-        let output: LlamaResponseMessage = chat_inner_async_llama(messages, max_token)
-            .await
-            .expect("Failed to generate reply");
-
-        Some(Message {
-            content: Some(output.content),
-            name: None,
-            role: None,
-        })
+        let mut messages = messages;
+        if let Some(memory) = &self.memory {
+            match memory.recall_note(&self.conversation_id, &messages, 3).await {
+                Ok(Some(note)) => {
+                    let at = messages
+                        .iter()
+                        .take_while(|m| m.role == Some(Role::System))
+                        .count();
+                    messages.insert(at, note);
+                }
+                Ok(None) => {}
+                Err(e) => println!("Error recalling memory of {}: {:?}", self.name, e),
+            }
+        }
+        // calls of registered tools are run here and answered until the model replies with
+        // something else
+        let mut tool_rounds = 0;
+        loop {
+            let output: LlamaResponseMessage = match self.chat(messages.clone(), max_token).await {
+                Ok(output) => output,
+                Err(e) => {
+                    println!("Error generating reply of {}: {:?}", self.name, e);
+                    return None;
+                }
+            };
+
+            if let Content::ToolCall(call) = &output.content {
+                if self.tool_handlers.contains_key(&call.name)
+                    && tool_rounds < self.max_consecutive_auto_reply
+                {
+                    let result = match self.execute_tool_call(call).await {
+                        Ok(result) => result,
+                        Err(error) => error,
+                    };
+                    messages.push(Message::new(
+                        Some(output.content.clone()),
+                        None,
+                        Some(Role::Assistant),
+                    ));
+                    messages.push(Message::new(
+                        Some(Content::Text(result)),
+                        Some(call.name.clone()),
+                        Some(Role::Tool),
+                    ));
+                    tool_rounds += 1;
+                    continue;
+                }
+            }
+
+            return Some(Message {
+                content: Some(output.content),
+                name: None,
+                role: None,
+            });
+        }
     }
 
     pub async fn update_system_message(&mut self, system_message: String) {
@@ -191,11 +304,17 @@ impl ConversableAgent {
             },
         ];
 
-        let code = chat_inner_async_llama(messages, 1000u16).await?;
+        let code = self.chat(messages, 1000u16).await?;
 
         let content = match code.content {
             Content::Text(c) => c,
-            Content::ToolCall(_) => panic!(),
+            Content::ToolCall(call) => {
+                return Err(anyhow::anyhow!(
+                    "expected code from {}, got a call of tool {}",
+                    self.name,
+                    call.name
+                ))
+            }
         };
 
         Ok(content)
@@ -206,30 +325,123 @@ impl ConversableAgent {
 
         let code = extract_code(&raw);
 
-        match run_python_capture(&code) {
-            Ok(res) => todo!(),
-
-            Err(e) => todo!(),
-        }
+        let agent_name = self.name.clone();
+        let conversation_id = self.conversation_id.clone();
+        let audit_log = self.audit_log.clone();
+        tokio::task::spawn_blocking(move || {
+            run_python_audited(
+                &code,
+                &agent_name,
+                Some(&conversation_id),
+                audit_log.as_deref(),
+            )
+        })
+        .await?
+        .map_err(|e| anyhow::anyhow!(e))
     }
 
-    // pub async fn execute_tool_call(&self, call: &ToolCall) -> anyhow::Result<String, String> {
-    //     let func = call.name.clone();
-    //     let args = call.arguments.unwrap_or_default();
+    // Offers the tool to the model and runs it when the model calls it. The definition is a
+    // function signature, bare or wrapped in {"type": "function", "function": ...}.
+    pub fn register_tool<F, Fut>(&mut self, definition: Value, handler: F) -> anyhow::Result<()>
+    where
+        F: Fn(Context) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = anyhow::Result<String>> + Send + 'static,
+    {
+        let name = definition
+            .get("function")
+            .unwrap_or(&definition)
+            .get("name")
+            .and_then(Value::as_str)
+            .ok_or_else(|| anyhow::anyhow!("Tool definition without a name: {}", definition))?
+            .to_string();
+        self.tools.push(definition);
+        self.tool_handlers
+            .insert(name, Arc::new(move |arguments| Box::pin(handler(arguments))));
+        Ok(())
+    }
 
-    //     let len = args.len();
+    // Runs a registered tool and leaves a record of it in the audit log, when there is one.
+    pub async fn execute_tool_call(&self, call: &ToolCall) -> Result<String, String> {
+        let started = std::time::Instant::now();
+        let outcome = match self.tool_handlers.get(&call.name) {
+            Some(handler) => handler(call.arguments.clone().unwrap_or_default())
+                .await
+                .map_err(|e| format!("Error running tool {}: {}", call.name, e)),
+            None => Err(format!("Unknown tool: {}", call.name)),
+        };
 
-    //     match len {
-    //         0 => call_function!(&func),
-    //         1 => call_function!(&func, args, single),
-    //         _ => call_function!(&func, args, multi),
-    //     }
-    // }
+        if let Some(audit_log) = &self.audit_log {
+            let mut record = ExecutionRecord::new(
+                &self.name,
+                ExecutionKind::ToolCall,
+                &call.name,
+                &tool_call_arguments_json(call),
+            );
+            record.conversation_id = Some(self.conversation_id.clone());
+            record.result = match &outcome {
+                Ok(result) => result.clone(),
+                Err(error) => error.clone(),
+            };
+            record.success = outcome.is_ok();
+            record.duration_ms = started.elapsed().as_millis() as i64;
+            record_async(audit_log.clone(), record).await;
+        }
+        outcome
+    }
 
     pub fn set_description(&mut self, description: String) {
         self.description = description;
     }
 
+    pub fn set_store(&mut self, store: Arc<dyn MessageStore>) {
+        self.store = Some(AsyncMessageStore::new(store));
+    }
+
+    pub fn set_memory(&mut self, memory: Arc<VectorMemory>) {
+        self.memory = Some(memory);
+    }
+
+    pub fn set_audit_log(&mut self, audit_log: Arc<dyn AuditLog>) {
+        self.audit_log = Some(audit_log);
+    }
+
+    pub fn set_model_client(&mut self, model_client: Arc<dyn ModelClient>) {
+        self.model_client = model_client;
+    }
+
+    pub fn set_cache_bypass(&mut self, cache_bypass: bool) {
+        self.cache_bypass = cache_bypass;
+    }
+
+    pub fn set_tools(&mut self, tools: Vec<Value>) {
+        self.tools = tools;
+    }
+
+    pub fn set_llm_endpoint(&mut self, endpoint: LlmClientConfig) {
+        self.model_client = Arc::new(OpenAiCompatibleClient::new(endpoint));
+    }
+
+    pub async fn chat(
+        &self,
+        messages: Vec<Message>,
+        max_token: u16,
+    ) -> anyhow::Result<LlamaResponseMessage> {
+        let model_client = match self.cache_bypass {
+            true => self
+                .model_client
+                .without_cache()
+                .unwrap_or_else(|| self.model_client.clone()),
+            false => self.model_client.clone(),
+        };
+        if self.tools.is_empty() {
+            model_client.chat(messages, max_token).await
+        } else {
+            model_client
+                .chat_with_tools(messages, &self.tools, max_token)
+                .await
+        }
+    }
+
     pub fn last_message(&self) -> Option<Message> {
         match &self.chat_messages {
             Some(messages) => messages.last().cloned(),
@@ -1,3 +1,4 @@
+use crate::audit_log::{AuditLog, ExecutionKind, ExecutionRecord};
 use anyhow;
 use regex::Regex;
 use rustpython::vm::Settings;
@@ -47,6 +48,106 @@ pub fn run_python_capture(code: &str) -> anyhow::Result<String, String> {
     })
 }
 
+#[derive(Debug, Clone, PartialEq)]
+pub struct PythonOutput {
+    pub stdout: String,
+    pub stderr: String,
+    // compilation or execution error, None when the code ran through
+    pub error: Option<String>,
+}
+
+fn exception_message(e: &vm::builtins::PyBaseExceptionRef) -> String {
+    match e.args().as_slice().first() {
+        Some(arg) => arg
+            .downcast_ref::<vm::builtins::PyStr>()
+            .map(|s| s.to_string())
+            .unwrap_or_else(|| "Unknown error".to_string()),
+        None => "No error message available".to_string(),
+    }
+}
+
+// Like run_python_capture, but keeps stdout and stderr apart and holds on to whatever was
+// printed before an error.
+pub fn run_python_with_output(code: &str) -> PythonOutput {
+    let interpreter = InterpreterConfig::new().init_stdlib().interpreter();
+    interpreter.enter(|vm| {
+        let scope = vm.new_scope_with_builtins();
+        let setup = "import io\nimport sys\n_captured_stdout = io.StringIO()\n_captured_stderr = io.StringIO()\nsys.stdout = _captured_stdout\nsys.stderr = _captured_stderr";
+        if let Err(e) = vm.run_code_string(scope.clone(), setup, "<setup>".to_owned()) {
+            return PythonOutput {
+                stdout: String::new(),
+                stderr: String::new(),
+                error: Some(format!("Failed to capture output: {}", exception_message(&e))),
+            };
+        }
+
+        let error = match vm.compile(code, vm::compiler::Mode::Exec, "<embedded>".to_owned()) {
+            Ok(code_obj) => match vm.run_code_obj(code_obj, scope.clone()) {
+                Ok(_) => None,
+                Err(e) => Some(format!(
+                    "Code execution error message: {}",
+                    exception_message(&e)
+                )),
+            },
+            Err(err) => Some(format!("Compilation error: {}", err)),
+        };
+
+        let read = |buffer: &str| -> String {
+            vm.run_code_string(
+                scope.clone(),
+                &format!("_captured_text = {}.getvalue()", buffer),
+                "<capture>".to_owned(),
+            )
+            .ok()
+            .and_then(|_| scope.globals.get_item("_captured_text", vm).ok())
+            .and_then(|text| {
+                text.downcast_ref::<vm::builtins::PyStr>()
+                    .map(|s| s.as_str().to_string())
+            })
+            .unwrap_or_default()
+        };
+        let stdout = read("_captured_stdout");
+        let mut stderr = read("_captured_stderr");
+        if let Some(error) = &error {
+            stderr.push_str(error);
+        }
+        PythonOutput {
+            stdout,
+            stderr,
+            error,
+        }
+    })
+}
+
+// Runs the code and leaves a record of it in the audit log, when there is one.
+pub fn run_python_audited(
+    code: &str,
+    agent_name: &str,
+    conversation_id: Option<&str>,
+    audit_log: Option<&dyn AuditLog>,
+) -> Result<String, String> {
+    let started = std::time::Instant::now();
+    let output = run_python_with_output(code);
+
+    if let Some(audit_log) = audit_log {
+        let mut record = ExecutionRecord::new(agent_name, ExecutionKind::Python, "python", code);
+        record.conversation_id = conversation_id.map(String::from);
+        record.stdout = output.stdout.clone();
+        record.stderr = output.stderr.clone();
+        record.result = output.error.clone().unwrap_or_else(|| output.stdout.clone());
+        record.duration_ms = started.elapsed().as_millis() as i64;
+        record.success = output.error.is_none();
+        if let Err(e) = audit_log.record(record) {
+            println!("Error writing audit record: {:?}", e);
+        }
+    }
+
+    match output.error {
+        Some(error) => Err(error),
+        None => Ok(output.stdout),
+    }
+}
+
 pub fn run_python(code: &str) -> anyhow::Result<String, String> {
     let interpreter = InterpreterConfig::new().init_stdlib().interpreter();
     interpreter.enter(|vm| {
@@ -0,0 +1,290 @@
+use crate::conversable_agent::*;
+use crate::llama_structs::Content;
+use crate::model_client::ModelClient;
+use crate::FAN_OUT_SYNTHESIS_TEMPLATE;
+use async_openai::types::Role;
+use futures::future::join_all;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Aggregation {
+    Concatenate,
+    Synthesize,
+    MajorityVote,
+}
+
+#[derive(Debug, Clone)]
+pub struct FanOutReply {
+    pub agent_name: String,
+    pub result: Result<Message, String>,
+}
+
+// Sends the same message to every agent at once. Each agent runs on its own task with its
+// own timeout, so a slow, failing or panicking agent only costs its own answer.
+pub async fn fan_out(
+    agents: &[Arc<dyn Agent>],
+    message: Message,
+    timeout: Duration,
+) -> Vec<FanOutReply> {
+    let handles = agents.iter().map(|agent| {
+        let agent = agent.clone();
+        let prompt = vec![
+            Message::new(
+                Some(Content::Text(agent.system_message())),
+                None,
+                Some(Role::System),
+            ),
+            message.clone(),
+        ];
+        let agent_name = agent.name();
+
+        let task = tokio::spawn(async move {
+            tokio::time::timeout(timeout, agent.a_generate_reply(prompt, None)).await
+        });
+
+        async move {
+            let result = match task.await {
+                Ok(Ok(Some(mut reply))) => {
+                    reply.name = Some(agent_name.clone());
+                    reply.role = Some(Role::Assistant);
+                    Ok(reply)
+                }
+                Ok(Ok(None)) => Err("no reply".to_string()),
+                Ok(Err(_)) => Err(format!("timed out after {:?}", timeout)),
+                Err(e) => Err(format!("agent task failed: {}", e)),
+            };
+            FanOutReply { agent_name, result }
+        }
+    });
+
+    join_all(handles).await
+}
+
+// Two answers vote alike when they are equal apart from case, whitespace and trailing
+// punctuation; answers that only share a first line are different votes.
+fn vote_key(text: &str) -> String {
+    text.split_whitespace()
+        .collect::<Vec<&str>>()
+        .join(" ")
+        .trim_end_matches(|c: char| c.is_ascii_punctuation())
+        .to_lowercase()
+}
+
+// `model_client` writes the synthesis; the other aggregations do not call a model.
+pub async fn aggregate(
+    model_client: &dyn ModelClient,
+    request: &Message,
+    replies: &[FanOutReply],
+    aggregation: Aggregation,
+) -> Option<Message> {
+    let answers: Vec<(&str, String)> = replies
+        .iter()
+        .filter_map(|reply| match &reply.result {
+            Ok(message) => message
+                .content_to_string()
+                .map(|text| (reply.agent_name.as_str(), text)),
+            Err(_) => None,
+        })
+        .collect();
+    if answers.is_empty() {
+        return None;
+    }
+
+    let combined = match aggregation {
+        Aggregation::Concatenate => answers
+            .iter()
+            .map(|(name, text)| format!("{}: {}", name, text))
+            .collect::<Vec<String>>()
+            .join("\n\n"),
+
+        Aggregation::MajorityVote => {
+            let mut tally: HashMap<String, (usize, usize)> = HashMap::new();
+            for (position, (_, text)) in answers.iter().enumerate() {
+                tally.entry(vote_key(text)).or_insert((0, position)).0 += 1;
+            }
+            // ties go to the answer that arrived first in agent order
+            let (_, (_, position)) = tally
+                .into_iter()
+                .max_by_key(|(_, (votes, position))| (*votes, std::cmp::Reverse(*position)))?;
+            answers[position].1.clone()
+        }
+
+        Aggregation::Synthesize => {
+            let rendered = answers
+                .iter()
+                .map(|(name, text)| format!("{}: {}", name, text))
+                .collect::<Vec<String>>()
+                .join("\n");
+            let system_prompt = {
+                let template = FAN_OUT_SYNTHESIS_TEMPLATE.lock().unwrap();
+                template(&[&request.content_to_string().unwrap_or_default(), &rendered])
+            };
+            let prompt = vec![Message::new(
+                Some(Content::Text(system_prompt)),
+                None,
+                Some(Role::System),
+            )];
+
+            match model_client.chat(prompt, 1000u16).await {
+                Ok(res) => match res.content {
+                    Content::Text(text) => text,
+                    Content::ToolCall(_) => return None,
+                },
+                Err(e) => {
+                    println!("Error synthesizing fan-out answers: {:?}", e);
+                    return None;
+                }
+            }
+        }
+    };
+
+    Some(Message::new(
+        Some(Content::Text(combined)),
+        None,
+        Some(Role::Assistant),
+    ))
+}
+
+pub async fn fan_out_and_aggregate(
+    model_client: &dyn ModelClient,
+    agents: &[Arc<dyn Agent>],
+    message: Message,
+    timeout: Duration,
+    aggregation: Aggregation,
+) -> (Vec<FanOutReply>, Option<Message>) {
+    let replies = fan_out(agents, message.clone(), timeout).await;
+    let combined = aggregate(model_client, &message, &replies, aggregation).await;
+    (replies, combined)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llama_structs::LlamaResponseMessage;
+    use crate::model_client::ScriptedModelClient;
+    use async_trait::async_trait;
+
+    struct SlowClient {
+        delay: Duration,
+        inner: ScriptedModelClient,
+    }
+
+    #[async_trait]
+    impl ModelClient for SlowClient {
+        async fn complete(
+            &self,
+            messages: Vec<Message>,
+            max_token: u16,
+        ) -> anyhow::Result<LlamaResponseMessage> {
+            tokio::time::sleep(self.delay).await;
+            self.inner.complete(messages, max_token).await
+        }
+    }
+
+    fn agent(name: &str, client: Arc<dyn ModelClient>) -> Arc<dyn Agent> {
+        let mut agent = ConversableAgent::new(name);
+        agent.set_model_client(client);
+        Arc::new(agent)
+    }
+
+    fn scripted(name: &str, replies: &[&str]) -> Arc<dyn Agent> {
+        agent(name, Arc::new(ScriptedModelClient::from_texts(replies)))
+    }
+
+    fn answer(agent_name: &str, text: &str) -> FanOutReply {
+        FanOutReply {
+            agent_name: agent_name.to_string(),
+            result: Ok(Message::new(
+                Some(Content::Text(text.to_string())),
+                Some(agent_name.to_string()),
+                Some(Role::Assistant),
+            )),
+        }
+    }
+
+    fn question() -> Message {
+        Message::new(
+            Some(Content::Text("What is 6 * 7?".to_string())),
+            None,
+            Some(Role::User),
+        )
+    }
+
+    #[tokio::test]
+    async fn slow_and_failing_agents_only_lose_their_own_answer() {
+        let agents = vec![
+            scripted("fast", &["42"]),
+            agent(
+                "slow",
+                Arc::new(SlowClient {
+                    delay: Duration::from_secs(5),
+                    inner: ScriptedModelClient::from_texts(&["42"]),
+                }),
+            ),
+            // nothing scripted, so the reply fails
+            scripted("broken", &[]),
+        ];
+
+        let replies = fan_out(&agents, question(), Duration::from_millis(200)).await;
+        let names: Vec<&str> = replies.iter().map(|r| r.agent_name.as_str()).collect();
+        assert_eq!(names, vec!["fast", "slow", "broken"]);
+        let answer = replies[0].result.as_ref().unwrap();
+        assert_eq!(answer.content, Some(Content::Text("42".to_string())));
+        assert_eq!(answer.name.as_deref(), Some("fast"));
+        assert!(replies[1]
+            .result
+            .as_ref()
+            .unwrap_err()
+            .starts_with("timed out"));
+        assert_eq!(replies[2].result.as_ref().unwrap_err(), "no reply");
+
+        let client = ScriptedModelClient::default();
+        let combined = aggregate(&client, &question(), &replies, Aggregation::Concatenate)
+            .await
+            .unwrap();
+        assert_eq!(
+            combined.content,
+            Some(Content::Text("fast: 42".to_string()))
+        );
+    }
+
+    #[tokio::test]
+    async fn majority_vote_compares_whole_answers_and_ties_go_to_the_first() {
+        let client = ScriptedModelClient::default();
+        let replies = vec![
+            answer("a", "Plan:\nuse a queue"),
+            answer("b", "Plan:\nuse a stack"),
+            answer("c", "plan:  use a STACK."),
+        ];
+        let winner = aggregate(&client, &question(), &replies, Aggregation::MajorityVote)
+            .await
+            .unwrap();
+        assert_eq!(
+            winner.content,
+            Some(Content::Text("Plan:\nuse a stack".to_string()))
+        );
+
+        let tied = vec![answer("a", "41"), answer("b", "42")];
+        let winner = aggregate(&client, &question(), &tied, Aggregation::MajorityVote)
+            .await
+            .unwrap();
+        assert_eq!(winner.content, Some(Content::Text("41".to_string())));
+    }
+
+    #[tokio::test]
+    async fn the_given_client_writes_the_synthesis() {
+        let client = ScriptedModelClient::from_texts(&["Both say 42."]);
+        let replies = vec![answer("a", "42"), answer("b", "forty-two")];
+        let combined = aggregate(&client, &question(), &replies, Aggregation::Synthesize)
+            .await
+            .unwrap();
+        assert_eq!(
+            combined.content,
+            Some(Content::Text("Both say 42.".to_string()))
+        );
+        let prompt = client.requests()[0][0].content_to_string().unwrap();
+        assert!(prompt.contains("a: 42\nb: forty-two"));
+    }
+}
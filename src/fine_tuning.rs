@@ -0,0 +1,371 @@
+use crate::conversable_agent::Message;
+use crate::llama_structs::{parse_tool_arguments, tool_call_arguments_json, Content, ToolCall};
+use crate::message_store::{MessageStore, StoredMessage};
+use async_openai::types::Role;
+use serde_json::{json, Map, Value};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+// Which stored conversations become training examples. With `agent_name` set, the example
+// is written from that agent's point of view: its own turns are the assistant, everybody
+// else speaks as user, and conversations it never spoke in are skipped.
+#[derive(Debug, Clone, Default)]
+pub struct FineTuneFilter {
+    pub conversation_ids: Option<Vec<String>>,
+    pub agent_name: Option<String>,
+    // conversations without a rating are left out as soon as this is set
+    pub min_rating: Option<i64>,
+    // tool definitions to attach; derived from the recorded calls when missing
+    pub tools: Option<Vec<Value>>,
+}
+
+fn role_name(role: Option<Role>) -> &'static str {
+    match role {
+        Some(Role::System) => "system",
+        Some(Role::Assistant) => "assistant",
+        Some(Role::Tool) | Some(Role::Function) => "tool",
+        _ => "user",
+    }
+}
+
+fn tool_definitions(calls: &BTreeMap<String, BTreeSet<String>>) -> Vec<Value> {
+    calls
+        .iter()
+        .map(|(name, keys)| {
+            let properties: Map<String, Value> = keys
+                .iter()
+                .map(|key| (key.clone(), json!({ "type": "string" })))
+                .collect();
+            json!({
+                "type": "function",
+                "function": {
+                    "name": name,
+                    "parameters": { "type": "object", "properties": properties },
+                }
+            })
+        })
+        .collect()
+}
+
+// One JSONL line in the OpenAI chat fine-tuning shape, or None when nothing in the
+// conversation would be learned as an assistant turn.
+pub fn fine_tuning_example(messages: &[StoredMessage], filter: &FineTuneFilter) -> Option<Value> {
+    if let Some(agent) = &filter.agent_name {
+        if !messages.iter().any(|stored| &stored.agent_name == agent) {
+            return None;
+        }
+    }
+
+    let mut lines: Vec<Value> = Vec::new();
+    // open tool calls as (id, name), answered by the next tool message with that name
+    let mut open_calls: Vec<(String, String)> = Vec::new();
+    let mut seen_calls: BTreeMap<String, BTreeSet<String>> = BTreeMap::new();
+
+    for stored in messages {
+        let message = &stored.message;
+        let role = match (&filter.agent_name, message.role) {
+            (_, Some(Role::System)) => "system",
+            (_, Some(Role::Tool)) | (_, Some(Role::Function)) => "tool",
+            (Some(agent), _) if &stored.agent_name == agent => "assistant",
+            (Some(_), _) => "user",
+            (None, role) => role_name(role),
+        };
+
+        match (&message.content, role) {
+            (Some(Content::ToolCall(tool_call)), "assistant") => {
+                let id = format!("call_{}", stored.seq);
+                seen_calls
+                    .entry(tool_call.name.clone())
+                    .or_default()
+                    .extend(
+                        tool_call
+                            .arguments
+                            .iter()
+                            .flatten()
+                            .map(|(key, _)| key.clone()),
+                    );
+                open_calls.push((id.clone(), tool_call.name.clone()));
+                lines.push(json!({
+                    "role": "assistant",
+                    "content": null,
+                    "tool_calls": [{
+                        "id": id,
+                        "type": "function",
+                        "function": {
+                            "name": tool_call.name,
+                            "arguments": tool_call_arguments_json(tool_call),
+                        }
+                    }]
+                }));
+            }
+            (_, "tool") => {
+                let text = message.content_to_string().unwrap_or_default();
+                let position = open_calls
+                    .iter()
+                    .rposition(|(_, name)| Some(name) == message.name.as_ref())
+                    .or_else(|| open_calls.len().checked_sub(1));
+                match position {
+                    Some(position) => {
+                        let (id, _) = open_calls.remove(position);
+                        lines.push(json!({ "role": "tool", "tool_call_id": id, "content": text }));
+                    }
+                    // a result nobody asked for in this example cannot be a tool message
+                    None => lines.push(json!({ "role": "user", "content": text })),
+                }
+            }
+            (content, role) => {
+                let text = match content {
+                    Some(Content::Text(text)) => text.clone(),
+                    _ => message.content_to_string().unwrap_or_default(),
+                };
+                lines.push(json!({ "role": role, "content": text }));
+            }
+        }
+    }
+
+    if !lines.iter().any(|line| line["role"] == "assistant") {
+        return None;
+    }
+
+    let mut example = json!({ "messages": lines });
+    let tools = match &filter.tools {
+        Some(tools) => tools.clone(),
+        None => tool_definitions(&seen_calls),
+    };
+    if !tools.is_empty() {
+        example["tools"] = Value::Array(tools);
+    }
+    Some(example)
+}
+
+pub fn export_fine_tuning_jsonl(
+    store: &dyn MessageStore,
+    filter: &FineTuneFilter,
+) -> anyhow::Result<String> {
+    let mut conversations = store.list_conversations(None)?;
+    // least recently updated first
+    conversations.reverse();
+
+    let mut jsonl = String::new();
+    for conversation in conversations {
+        if let Some(ids) = &filter.conversation_ids {
+            if !ids.contains(&conversation.id) {
+                continue;
+            }
+        }
+        if let Some(min_rating) = filter.min_rating {
+            if !matches!(conversation.rating, Some(rating) if rating >= min_rating) {
+                continue;
+            }
+        }
+        let messages: Vec<StoredMessage> = store
+            .load_conversation(&conversation.id)?
+            .into_iter()
+            .filter(StoredMessage::is_public)
+            .collect();
+        if let Some(example) = fine_tuning_example(&messages, filter) {
+            jsonl.push_str(&example.to_string());
+            jsonl.push('\n');
+        }
+    }
+    Ok(jsonl)
+}
+
+fn import_example(
+    store: &dyn MessageStore,
+    example: &Value,
+    session_id: Option<&str>,
+) -> anyhow::Result<String> {
+    let lines = example["messages"]
+        .as_array()
+        .ok_or_else(|| anyhow::anyhow!("no \"messages\" array"))?;
+    let conversation_id = store.create_conversation(session_id, None)?;
+    let mut call_names: HashMap<String, String> = HashMap::new();
+
+    for line in lines {
+        let role_text = line["role"].as_str().unwrap_or("user");
+        let role = match role_text {
+            "system" => Role::System,
+            "assistant" => Role::Assistant,
+            "tool" => Role::Tool,
+            "function" => Role::Function,
+            _ => Role::User,
+        };
+        let name = line["name"].as_str().map(String::from);
+        let agent_name = name.clone().unwrap_or_else(|| role_text.to_string());
+        let text = line["content"].as_str().map(String::from);
+
+        if role == Role::Tool || role == Role::Function {
+            let tool_name = line["tool_call_id"]
+                .as_str()
+                .and_then(|id| call_names.get(id).cloned())
+                .or(name);
+            store.save_message(
+                &conversation_id,
+                agent_name,
+                Message::new(
+                    Some(Content::Text(text.unwrap_or_default())),
+                    tool_name,
+                    Some(role),
+                ),
+                String::new(),
+            )?;
+            continue;
+        }
+
+        if let Some(text) = text.filter(|text| !text.is_empty()) {
+            store.save_message(
+                &conversation_id,
+                agent_name.clone(),
+                Message::new(Some(Content::Text(text)), name.clone(), Some(role)),
+                String::new(),
+            )?;
+        }
+        for call in line["tool_calls"].as_array().into_iter().flatten() {
+            let function = &call["function"];
+            let tool_name = function["name"].as_str().unwrap_or_default().to_string();
+            if let Some(id) = call["id"].as_str() {
+                call_names.insert(id.to_string(), tool_name.clone());
+            }
+            let tool_call = ToolCall {
+                name: tool_name,
+                arguments: function["arguments"]
+                    .as_str()
+                    .and_then(parse_tool_arguments),
+                id: None,
+            };
+            store.save_message(
+                &conversation_id,
+                agent_name.clone(),
+                Message::new(Some(Content::ToolCall(tool_call)), name.clone(), Some(role)),
+                String::new(),
+            )?;
+        }
+    }
+    Ok(conversation_id)
+}
+
+// Loads every line of a fine-tuning JSONL file as a new conversation and returns their ids.
+pub fn import_fine_tuning_jsonl(
+    store: &dyn MessageStore,
+    jsonl: &str,
+    session_id: Option<&str>,
+) -> anyhow::Result<Vec<String>> {
+    let mut conversation_ids = Vec::new();
+    for (index, line) in jsonl.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let example: Value =
+            serde_json::from_str(line).map_err(|e| anyhow::anyhow!("line {}: {}", index + 1, e))?;
+        let conversation_id = import_example(store, &example, session_id)
+            .map_err(|e| anyhow::anyhow!("line {}: {}", index + 1, e))?;
+        conversation_ids.push(conversation_id);
+    }
+    Ok(conversation_ids)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message_store::InMemoryMessageStore;
+
+    fn save(store: &dyn MessageStore, conversation_id: &str, agent: &str, message: Message) {
+        store
+            .save_message(conversation_id, agent.to_string(), message, String::new())
+            .unwrap();
+    }
+
+    fn text(content: &str, role: Role) -> Message {
+        Message::new(Some(Content::Text(content.to_string())), None, Some(role))
+    }
+
+    #[test]
+    fn export_filters_and_import_reads_the_lines_back() {
+        let store = InMemoryMessageStore::new();
+        let rated = store.create_conversation(None, None).unwrap();
+        save(
+            &store,
+            &rated,
+            "user",
+            text("Weather in Paris?", Role::User),
+        );
+        let mut arguments = HashMap::new();
+        arguments.insert("city".to_string(), "Paris".to_string());
+        save(
+            &store,
+            &rated,
+            "planner",
+            Message::new(
+                Some(Content::ToolCall(ToolCall {
+                    name: "get_weather".to_string(),
+                    arguments: Some(arguments),
+                    id: None,
+                })),
+                None,
+                Some(Role::Assistant),
+            ),
+        );
+        save(
+            &store,
+            &rated,
+            "planner",
+            Message::new(
+                Some(Content::Text("sunny".to_string())),
+                Some("get_weather".to_string()),
+                Some(Role::Tool),
+            ),
+        );
+        save(
+            &store,
+            &rated,
+            "planner",
+            text("It is sunny.", Role::Assistant),
+        );
+        store.rate_conversation(&rated, Some(5)).unwrap();
+
+        let unrated = store.create_conversation(None, None).unwrap();
+        save(&store, &unrated, "user", text("hi", Role::User));
+        save(&store, &unrated, "planner", text("hello", Role::Assistant));
+
+        let filter = FineTuneFilter {
+            min_rating: Some(4),
+            ..FineTuneFilter::default()
+        };
+        let jsonl = export_fine_tuning_jsonl(&store, &filter).unwrap();
+        assert_eq!(jsonl.lines().count(), 1);
+        let example: Value = serde_json::from_str(jsonl.trim()).unwrap();
+        assert_eq!(
+            example["messages"][1]["tool_calls"][0]["function"]["arguments"],
+            r#"{"city":"Paris"}"#
+        );
+        assert_eq!(
+            example["messages"][2]["tool_call_id"],
+            example["messages"][1]["tool_calls"][0]["id"]
+        );
+        assert_eq!(example["tools"][0]["function"]["name"], "get_weather");
+
+        let other_agent = FineTuneFilter {
+            agent_name: Some("reviewer".to_string()),
+            ..FineTuneFilter::default()
+        };
+        assert!(export_fine_tuning_jsonl(&store, &other_agent)
+            .unwrap()
+            .is_empty());
+
+        let imported = InMemoryMessageStore::new();
+        let ids = import_fine_tuning_jsonl(&imported, &jsonl, Some("training")).unwrap();
+        let original: Vec<Message> = store
+            .load_conversation(&rated)
+            .unwrap()
+            .into_iter()
+            .map(|stored| stored.message)
+            .collect();
+        let restored: Vec<Message> = imported
+            .load_conversation(&ids[0])
+            .unwrap()
+            .into_iter()
+            .map(|stored| stored.message)
+            .collect();
+        assert_eq!(restored, original);
+    }
+}
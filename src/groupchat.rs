@@ -1,24 +1,1080 @@
 use crate::conversable_agent::*;
-use std::collections::{HashMap, VecDeque};
+use crate::llama_structs::{Content, ToolCall};
+use crate::audit_log::{record_async, AuditLog, ExecutionKind, ExecutionRecord};
+use crate::message_store::{new_conversation_id, AsyncMessageStore, MessageMeta, MessageStore};
+use crate::model_client::{default_model_client, ModelClient};
+use crate::{
+    GROUP_CHAT_SUMMARY_TEMPLATE, HANDOFF_TOOLS_TEMPLATE, SPEAKER_CORRECTION_TEMPLATE,
+    SPEAKER_SELECTION_TEMPLATE,
+};
+use async_openai::types::Role;
+use async_trait::async_trait;
+use regex::Regex;
+use serde_json::{json, Value};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::sync::{Arc, Mutex};
 
 pub struct GroupChat {
-    pub agents: HashMap<String, Arc<ConversableAgent>>,
+    pub agents: HashMap<String, Arc<dyn Agent>>,
+    pub messages: Vec<Message>,
+    // the visibility each entry of `messages` was posted with
+    pub visibilities: Vec<Visibility>,
     pub messages_store: Arc<Mutex<HashMap<String, VecDeque<Message>>>>,
     pub next_speaker: Option<String>,
+    pub speaker_resolver: SpeakerResolver,
+    pub max_round: usize,
+    pub context: Context,
+    // handoff context from replies that were not broadcast, only for the agents that saw them
+    pub agent_context: HashMap<String, Context>,
+    pub role_groups: HashMap<String, HashSet<String>>,
+    pub reply_visibility: HashMap<String, Visibility>,
+    pub store: Option<AsyncMessageStore>,
+    pub conversation_id: String,
+    pub audit_log: Option<Arc<dyn AuditLog>>,
+    // used for speaker selection
+    pub model_client: Arc<dyn ModelClient>,
+}
+
+pub const HANDOFF_TOOL_PREFIX: &str = "transfer_to_";
+
+// Who gets a posted message in their queue, and thereby in their LLM prompt.
+// The sender always keeps its own messages.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Visibility {
+    Broadcast,
+    Direct(String),
+    RoleGroup(String),
+}
+
+impl Visibility {
+    // How a MessageStore records it: None for a broadcast, which anyone may read.
+    pub fn stored(&self) -> Option<String> {
+        match self {
+            Visibility::Broadcast => None,
+            Visibility::Direct(recipient) => Some(format!("direct:{}", recipient)),
+            Visibility::RoleGroup(group) => Some(format!("group:{}", group)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SpeakerMatch {
+    Resolved(String),
+    Ambiguous(Vec<String>),
+    NotFound,
+}
+
+// Turns the free-form reply of the speaker selection prompt into a registered agent name.
+// Small local models tend to add chatter, misspell names or list several candidates.
+#[derive(Debug, Clone)]
+pub struct SpeakerResolver {
+    pub max_retries: u8,
+    pub default_speaker: Option<String>,
+}
+
+impl Default for SpeakerResolver {
+    fn default() -> Self {
+        SpeakerResolver {
+            max_retries: 2,
+            default_speaker: None,
+        }
+    }
+}
+
+fn normalize_name(text: &str) -> String {
+    text.to_lowercase()
+        .split(|c: char| c.is_whitespace() || c == '_' || c == '-')
+        .filter(|part| !part.is_empty())
+        .collect::<Vec<&str>>()
+        .join(" ")
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b_chars: Vec<char> = b.chars().collect();
+    let mut prev: Vec<usize> = (0..=b_chars.len()).collect();
+
+    for (i, ca) in a.chars().enumerate() {
+        let mut curr = vec![i + 1; b_chars.len() + 1];
+        for (j, cb) in b_chars.iter().enumerate() {
+            let cost = if ca == *cb { 0 } else { 1 };
+            curr[j + 1] = (prev[j] + cost).min(prev[j + 1] + 1).min(curr[j] + 1);
+        }
+        prev = curr;
+    }
+
+    prev[b_chars.len()]
+}
+
+impl SpeakerResolver {
+    pub fn new(max_retries: u8, default_speaker: Option<String>) -> Self {
+        SpeakerResolver {
+            max_retries,
+            default_speaker,
+        }
+    }
+
+    pub fn count_mentions(reply: &str, names: &[String]) -> HashMap<String, usize> {
+        let mut haystack = format!(" {} ", normalize_name(reply));
+
+        // longer names first, so "senior coder" is not also counted as "coder"
+        let mut sorted: Vec<&String> = names.iter().collect();
+        sorted.sort_by_key(|name| std::cmp::Reverse(normalize_name(name).len()));
+
+        let mut counts = HashMap::new();
+        for name in sorted {
+            let needle = normalize_name(name);
+            if needle.is_empty() {
+                continue;
+            }
+            let pattern = Regex::new(&format!(r"\b{}\b", regex::escape(&needle))).unwrap();
+            let count = pattern.find_iter(&haystack).count();
+            if count > 0 {
+                haystack = pattern.replace_all(&haystack, " ").to_string();
+                counts.insert(name.clone(), count);
+            }
+        }
+        counts
+    }
+
+    fn fuzzy_match(reply: &str, names: &[String]) -> Option<String> {
+        let reply = normalize_name(reply);
+        let words: Vec<&str> = reply.split(' ').collect();
+
+        let hits: Vec<&String> = names
+            .iter()
+            .filter(|name| {
+                let needle = normalize_name(name);
+                let width = needle.split(' ').count();
+                let tolerance = (needle.chars().count() / 4).max(1);
+                words
+                    .windows(width.min(words.len()).max(1))
+                    .any(|window| edit_distance(&window.join(" "), &needle) <= tolerance)
+            })
+            .collect();
+
+        match hits.as_slice() {
+            [only] => Some((*only).clone()),
+            _ => None,
+        }
+    }
+
+    pub fn resolve(&self, reply: &str, names: &[String]) -> SpeakerMatch {
+        let counts = Self::count_mentions(reply, names);
+
+        if let Some(max) = counts.values().max().copied() {
+            let mut top: Vec<String> = counts
+                .into_iter()
+                .filter(|(_, count)| *count == max)
+                .map(|(name, _)| name)
+                .collect();
+            if top.len() == 1 {
+                return SpeakerMatch::Resolved(top.remove(0));
+            }
+            top.sort();
+            return SpeakerMatch::Ambiguous(top);
+        }
+
+        match Self::fuzzy_match(reply, names) {
+            Some(name) => SpeakerMatch::Resolved(name),
+            None => SpeakerMatch::NotFound,
+        }
+    }
+
+    pub fn fallback(&self, names: &[String]) -> Option<String> {
+        match &self.default_speaker {
+            Some(name) if names.contains(name) => Some(name.clone()),
+            _ => names.first().cloned(),
+        }
+    }
 }
 
 impl GroupChat {
     pub fn new() -> Self {
         GroupChat {
             agents: HashMap::new(),
+            messages: Vec::new(),
+            visibilities: Vec::new(),
             messages_store: Arc::new(Mutex::new(HashMap::new())),
             next_speaker: None,
+            speaker_resolver: SpeakerResolver::default(),
+            max_round: 10,
+            context: Context::new(),
+            agent_context: HashMap::new(),
+            role_groups: HashMap::new(),
+            reply_visibility: HashMap::new(),
+            store: None,
+            conversation_id: new_conversation_id(),
+            audit_log: None,
+            model_client: default_model_client(),
         }
     }
 
+    // The agent joins this chat's conversation, so what it saves and recalls from memory is
+    // what the chat stores.
     pub fn register(&mut self, agent: &ConversableAgent) {
-        let agent_arc = Arc::new(agent.clone());
-        self.agents.insert(agent.name.clone(), agent_arc);
+        let mut agent = agent.clone();
+        agent.conversation_id = self.conversation_id.clone();
+        self.agents.insert(agent.name.clone(), Arc::new(agent));
+    }
+
+    // Any Agent can take part, including a GroupChatManager fronting a nested team.
+    pub fn register_agent(&mut self, agent: Arc<dyn Agent>) {
+        self.agents.insert(agent.name(), agent);
+    }
+
+    pub fn agent_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.agents.keys().cloned().collect();
+        names.sort();
+        names
+    }
+
+    pub async fn select_speaker(&mut self, messages: &[Message]) -> Option<String> {
+        let names = self.agent_names();
+        if names.is_empty() {
+            return None;
+        }
+
+        let roles = names
+            .iter()
+            .map(|name| format!("{}: {}", name, self.agents[name].description()))
+            .collect::<Vec<String>>()
+            .join("\n");
+        let name_list = format!("[{}]", names.join(", "));
+
+        let system_prompt = {
+            let template = SPEAKER_SELECTION_TEMPLATE.lock().unwrap();
+            template(&[&roles, &name_list])
+        };
+
+        let mut prompt = vec![Message::new(
+            Some(Content::Text(system_prompt)),
+            None,
+            Some(Role::System),
+        )];
+        prompt.extend(messages.iter().cloned());
+
+        for _ in 0..=self.speaker_resolver.max_retries {
+            let reply = match self.model_client.chat(prompt.clone(), 50u16).await {
+                Ok(res) => match res.content {
+                    Content::Text(text) => text,
+                    Content::ToolCall(_) => String::new(),
+                },
+                Err(e) => {
+                    println!("Error selecting next speaker: {:?}", e);
+                    continue;
+                }
+            };
+
+            if let SpeakerMatch::Resolved(name) = self.speaker_resolver.resolve(&reply, &names) {
+                self.next_speaker = Some(name.clone());
+                return Some(name);
+            }
+
+            let correction = {
+                let template = SPEAKER_CORRECTION_TEMPLATE.lock().unwrap();
+                template(&[reply.trim(), &name_list])
+            };
+            prompt.push(Message::new(
+                Some(Content::Text(reply)),
+                None,
+                Some(Role::Assistant),
+            ));
+            prompt.push(Message::new(
+                Some(Content::Text(correction)),
+                None,
+                Some(Role::User),
+            ));
+        }
+
+        self.next_speaker = self.speaker_resolver.fallback(&names);
+        self.next_speaker.clone()
+    }
+
+    pub fn assign_role(&mut self, agent_name: &str, role_group: &str) {
+        self.role_groups
+            .entry(role_group.to_string())
+            .or_default()
+            .insert(agent_name.to_string());
+    }
+
+    // Replies of this agent are posted with the given visibility instead of being broadcast,
+    // e.g. a judge whose verdicts only the other judges can read.
+    pub fn set_reply_visibility(&mut self, agent_name: &str, visibility: Visibility) {
+        self.reply_visibility
+            .insert(agent_name.to_string(), visibility);
+    }
+
+    pub fn can_see(&self, agent_name: &str, sender: Option<&str>, visibility: &Visibility) -> bool {
+        if sender == Some(agent_name) {
+            return true;
+        }
+        match visibility {
+            Visibility::Broadcast => true,
+            Visibility::Direct(recipient) => recipient == agent_name,
+            Visibility::RoleGroup(group) => self
+                .role_groups
+                .get(group)
+                .is_some_and(|members| members.contains(agent_name)),
+        }
+    }
+
+    pub fn set_store(&mut self, store: Arc<dyn MessageStore>) {
+        self.store = Some(AsyncMessageStore::new(store));
+    }
+
+    pub fn set_audit_log(&mut self, audit_log: Arc<dyn AuditLog>) {
+        self.audit_log = Some(audit_log);
+    }
+
+    pub fn set_model_client(&mut self, model_client: Arc<dyn ModelClient>) {
+        self.model_client = model_client;
+    }
+
+    pub async fn post(&mut self, message: Message) {
+        self.post_with_visibility(message, Visibility::Broadcast).await;
+    }
+
+    pub async fn post_with_visibility(&mut self, message: Message, visibility: Visibility) {
+        {
+            let mut store = self.messages_store.lock().unwrap();
+            for name in self.agents.keys() {
+                if self.can_see(name, message.name.as_deref(), &visibility) {
+                    store
+                        .entry(name.clone())
+                        .or_default()
+                        .push_back(message.clone());
+                }
+            }
+        }
+        if let Some(store) = &self.store {
+            let sender = message.name.clone().unwrap_or_else(|| "user".to_string());
+            let next_speaker = self.next_speaker.clone().unwrap_or_default();
+            if let Err(e) = store
+                .save_message_with(
+                    &self.conversation_id,
+                    sender,
+                    message.clone(),
+                    next_speaker,
+                    MessageMeta {
+                        visibility: visibility.stored(),
+                        ..MessageMeta::default()
+                    },
+                )
+                .await
+            {
+                println!("Error saving group chat message: {:?}", e);
+            }
+        }
+        self.messages.push(message);
+        self.visibilities.push(visibility);
+    }
+
+    // The broadcast messages from `start` on: what the speaker selection, the caller of a run
+    // and anyone summarizing it may read.
+    pub fn public_messages(&self, start: usize) -> Vec<Message> {
+        self.messages[start..]
+            .iter()
+            .zip(&self.visibilities[start..])
+            .filter(|(_, visibility)| **visibility == Visibility::Broadcast)
+            .map(|(message, _)| message.clone())
+            .collect()
+    }
+
+    async fn post_reply(&mut self, reply: Message) {
+        let visibility = reply
+            .name
+            .as_ref()
+            .and_then(|name| self.reply_visibility.get(name))
+            .cloned()
+            .unwrap_or(Visibility::Broadcast);
+        self.post_with_visibility(reply, visibility).await;
+    }
+
+    // What one agent may read of the chat, seen from its own perspective: its earlier turns
+    // are assistant messages, everybody else speaks as user. System and tool messages keep
+    // their roles.
+    pub fn history_for(&self, agent_name: &str) -> Vec<Message> {
+        self.messages
+            .iter()
+            .zip(&self.visibilities)
+            .filter(|(message, visibility)| {
+                self.can_see(agent_name, message.name.as_deref(), visibility)
+            })
+            .map(|(message, _)| {
+                let mut message = message.clone();
+                if !matches!(message.role, Some(Role::System) | Some(Role::Tool)) {
+                    message.role = if message.name.as_deref() == Some(agent_name) {
+                        Some(Role::Assistant)
+                    } else {
+                        Some(Role::User)
+                    };
+                }
+                message
+            })
+            .collect()
+    }
+
+    pub async fn run(&mut self, messages: Vec<Message>) -> Vec<Message> {
+        let start = self.messages.len();
+        for message in messages {
+            self.post(message).await;
+        }
+
+        for _ in 0..self.max_round {
+            let speaker = if self.agents.len() == 1 {
+                self.agent_names().pop()
+            } else {
+                let transcript = self.public_messages(0);
+                self.select_speaker(&transcript).await
+            };
+            let Some(speaker) = speaker else {
+                break;
+            };
+            let agent = self.agents[&speaker].clone();
+
+            let mut prompt = vec![Message::new(
+                Some(Content::Text(agent.system_message())),
+                None,
+                Some(Role::System),
+            )];
+            prompt.extend(self.history_for(&speaker));
+
+            let Some(mut reply) = agent.a_generate_reply(prompt, None).await else {
+                break;
+            };
+            reply.name = Some(speaker);
+            reply.role = Some(Role::Assistant);
+
+            let done = reply
+                .content_to_string()
+                .is_some_and(|text| text.contains("TERMINATE"));
+            self.post_reply(reply).await;
+            if done {
+                break;
+            }
+        }
+
+        self.public_messages(start)
+    }
+
+    // One transfer_to_<agent> signature per registered agent except the caller.
+    pub fn handoff_tools(&self, from_agent: &str) -> Vec<Value> {
+        self.agent_names()
+            .into_iter()
+            .filter(|name| name != from_agent)
+            .map(|name| {
+                json!({
+                    "name": format!("{}{}", HANDOFF_TOOL_PREFIX, name),
+                    "description": format!(
+                        "Hand the conversation over to {}: {}",
+                        name,
+                        self.agents[&name].description()
+                    ),
+                    "parameters": {
+                        "type": "object",
+                        "properties": {
+                            "context": {
+                                "type": "string",
+                                "description": format!("What {} needs to know to continue", name)
+                            }
+                        },
+                        "required": []
+                    }
+                })
+            })
+            .collect()
+    }
+
+    pub fn handoff_target(&self, tool_call: &ToolCall) -> Option<String> {
+        let target = tool_call.name.strip_prefix(HANDOFF_TOOL_PREFIX)?;
+        if self.agents.contains_key(target) {
+            return Some(target.to_string());
+        }
+        self.agent_names()
+            .into_iter()
+            .find(|name| normalize_name(name) == normalize_name(target))
+    }
+
+    fn swarm_system_prompt(&self, agent: &Arc<dyn Agent>) -> String {
+        let tools = self
+            .handoff_tools(&agent.name())
+            .iter()
+            .map(|tool| tool.to_string())
+            .collect::<Vec<String>>()
+            .join("\n");
+        let mut context = self.context.clone();
+        if let Some(private) = self.agent_context.get(&agent.name()) {
+            context.extend(private.clone());
+        }
+        let context = serde_json::to_string(&context).unwrap_or_default();
+
+        let handoff_prompt = {
+            let template = HANDOFF_TOOLS_TEMPLATE.lock().unwrap();
+            template(&[&tools, &context])
+        };
+        format!("{}\n{}", agent.system_message(), handoff_prompt)
+    }
+
+    // Handoff context goes where the handoff itself went: to everybody when it was broadcast,
+    // otherwise only to the agents that could see it.
+    fn share_context(&mut self, sender: &str, visibility: &Visibility, arguments: Context) {
+        if *visibility == Visibility::Broadcast {
+            self.context.extend(arguments);
+            return;
+        }
+        for name in self.agent_names() {
+            if self.can_see(&name, Some(sender), visibility) {
+                self.agent_context
+                    .entry(name)
+                    .or_default()
+                    .extend(arguments.clone());
+            }
+        }
+    }
+
+    // Agents pass control to each other directly by calling a generated transfer_to_<agent>
+    // tool. The run ends when the active agent answers without handing over.
+    pub async fn run_swarm(&mut self, initial_agent: &str, messages: Vec<Message>) -> Vec<Message> {
+        let start = self.messages.len();
+        for message in messages {
+            self.post(message).await;
+        }
+
+        let mut active = initial_agent.to_string();
+        for _ in 0..self.max_round {
+            let Some(agent) = self.agents.get(&active).cloned() else {
+                break;
+            };
+            self.next_speaker = Some(active.clone());
+
+            let mut prompt = vec![Message::new(
+                Some(Content::Text(self.swarm_system_prompt(&agent))),
+                None,
+                Some(Role::System),
+            )];
+            prompt.extend(self.history_for(&active));
+
+            let Some(mut reply) = agent.a_generate_reply(prompt, None).await else {
+                break;
+            };
+            reply.name = Some(active.clone());
+            reply.role = Some(Role::Assistant);
+
+            let tool_call = match &reply.content {
+                Some(Content::ToolCall(tool_call)) => Some(tool_call.clone()),
+                _ => None,
+            };
+            let visibility = self
+                .reply_visibility
+                .get(&active)
+                .cloned()
+                .unwrap_or(Visibility::Broadcast);
+            self.post_reply(reply).await;
+
+            let Some(tool_call) = tool_call else {
+                break;
+            };
+            let started = std::time::Instant::now();
+            let mut record = ExecutionRecord::new(
+                &active,
+                ExecutionKind::ToolCall,
+                &tool_call.name,
+                &serde_json::to_string(&tool_call.arguments).unwrap_or_default(),
+            );
+            record.conversation_id = Some(self.conversation_id.clone());
+            match self.handoff_target(&tool_call) {
+                Some(target) => {
+                    if let Some(arguments) = tool_call.arguments {
+                        self.share_context(&active, &visibility, arguments);
+                    }
+                    record.result = format!("handed off to {}", target);
+                    record.success = true;
+                    active = target;
+                }
+                None => {
+                    let note = format!(
+                        "{} is not an available handoff; use one of: {}",
+                        tool_call.name,
+                        self.handoff_tools(&active)
+                            .iter()
+                            .filter_map(|tool| tool["name"].as_str().map(String::from))
+                            .collect::<Vec<String>>()
+                            .join(", ")
+                    );
+                    record.result = note.clone();
+                    self.post_with_visibility(
+                        Message::new(
+                            Some(Content::Text(note)),
+                            Some(tool_call.name),
+                            Some(Role::Tool),
+                        ),
+                        Visibility::Direct(active.clone()),
+                    )
+                    .await;
+                }
+            }
+            if let Some(audit_log) = &self.audit_log {
+                record.duration_ms = started.elapsed().as_millis() as i64;
+                record_async(audit_log.clone(), record).await;
+            }
+        }
+
+        self.public_messages(start)
+    }
+}
+
+// Wraps a GroupChat so that a whole team can sit in an outer GroupChat as a single agent.
+// Asked for a reply, it runs the inner rounds and answers with a summary of them.
+pub struct GroupChatManager {
+    pub name: String,
+    pub description: String,
+    pub system_message: String,
+    pub group_chat: Arc<tokio::sync::Mutex<GroupChat>>,
+    // writes the summary; the one of the inner GroupChat unless replaced
+    pub model_client: Arc<dyn ModelClient>,
+    // how many outer messages the inner GroupChat has already been given
+    forwarded: Mutex<usize>,
+}
+
+impl GroupChatManager {
+    pub fn new(name: &str, group_chat: GroupChat) -> Self {
+        GroupChatManager {
+            name: name.to_string(),
+            description: format!(
+                "team {} made of {}",
+                name,
+                group_chat.agent_names().join(", ")
+            ),
+            system_message: String::from("you coordinate a team of agents"),
+            model_client: group_chat.model_client.clone(),
+            group_chat: Arc::new(tokio::sync::Mutex::new(group_chat)),
+            forwarded: Mutex::new(0),
+        }
+    }
+
+    pub async fn summarize(&self, transcript: &[Message]) -> Option<String> {
+        let rendered = transcript
+            .iter()
+            .filter_map(|message| {
+                let speaker = message.name.clone().unwrap_or_else(|| "user".to_string());
+                message
+                    .content_to_string()
+                    .map(|text| format!("{}: {}", speaker, text))
+            })
+            .collect::<Vec<String>>()
+            .join("\n");
+
+        let system_prompt = {
+            let template = GROUP_CHAT_SUMMARY_TEMPLATE.lock().unwrap();
+            template(&[&self.name, &rendered])
+        };
+        let prompt = vec![Message::new(
+            Some(Content::Text(system_prompt)),
+            None,
+            Some(Role::System),
+        )];
+
+        match self.model_client.chat(prompt, 1000u16).await {
+            Ok(res) => match res.content {
+                Content::Text(text) => Some(text),
+                Content::ToolCall(_) => None,
+            },
+            Err(e) => {
+                println!("Error summarizing group chat {}: {:?}", self.name, e);
+                None
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Agent for GroupChatManager {
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn description(&self) -> String {
+        self.description.clone()
+    }
+
+    fn system_message(&self) -> String {
+        self.system_message.clone()
+    }
+
+    fn set_description(&mut self, description: String) {
+        self.description = description;
+    }
+
+    async fn a_generate_reply(
+        &self,
+        messages: Vec<Message>,
+        _sender: Option<Arc<ConversableAgent>>,
+    ) -> Option<Message> {
+        // the outer chat prepends our own system message; the inner agents bring their own
+        let outer: Vec<Message> = messages
+            .into_iter()
+            .filter(|message| message.role != Some(Role::System))
+            .collect();
+
+        let mut group_chat = self.group_chat.lock().await;
+        // The inner chat keeps what it was given before, so only what is new goes in. A shorter
+        // history than last time is a different conversation and goes in whole. Our own
+        // summaries come from the inner chat to begin with.
+        let task: Vec<Message> = {
+            let mut forwarded = self.forwarded.lock().unwrap();
+            let start = match outer.len() < *forwarded {
+                true => 0,
+                false => *forwarded,
+            };
+            *forwarded = outer.len();
+            outer
+                .into_iter()
+                .skip(start)
+                .filter(|message| message.name.as_deref() != Some(self.name.as_str()))
+                .collect()
+        };
+        let transcript = group_chat.run(task).await;
+        drop(group_chat);
+        if transcript.is_empty() {
+            return None;
+        }
+
+        let summary = match self.summarize(&transcript).await {
+            Some(summary) => summary,
+            None => transcript.last()?.content_to_string()?,
+        };
+
+        Some(Message::new(
+            Some(Content::Text(summary)),
+            Some(self.name.clone()),
+            Some(Role::Assistant),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm_llama_local::to_native_tools;
+    use crate::model_client::ScriptedModelClient;
+
+    fn scripted_agent(name: &str, replies: &[&str]) -> ConversableAgent {
+        let mut agent = ConversableAgent::new(name);
+        agent.set_model_client(Arc::new(ScriptedModelClient::from_texts(replies)));
+        agent
+    }
+
+    fn user(text: &str) -> Message {
+        Message::new(Some(Content::Text(text.to_string())), None, Some(Role::User))
+    }
+
+    #[tokio::test]
+    async fn a_manager_forwards_each_outer_message_once() {
+        let mut group = GroupChat::new();
+        group.register(&scripted_agent(
+            "writer",
+            &["First draft. TERMINATE", "Second draft. TERMINATE"],
+        ));
+        group.set_model_client(Arc::new(ScriptedModelClient::from_texts(&[
+            "summary one",
+            "summary two",
+        ])));
+        let manager = GroupChatManager::new("team", group);
+        let system = Message::new(
+            Some(Content::Text("coordinate".to_string())),
+            None,
+            Some(Role::System),
+        );
+
+        let mut outer = vec![system.clone(), user("Write a poem")];
+        let reply = manager.a_generate_reply(outer.clone(), None).await.unwrap();
+        outer.push(reply);
+        outer.push(user("Make it shorter"));
+        manager.a_generate_reply(outer, None).await.unwrap();
+
+        let inner: Vec<String> = manager
+            .group_chat
+            .lock()
+            .await
+            .messages
+            .iter()
+            .filter_map(|message| message.content_to_string())
+            .collect();
+        assert_eq!(
+            inner,
+            vec![
+                "Write a poem",
+                "First draft. TERMINATE",
+                "Make it shorter",
+                "Second draft. TERMINATE",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn run_follows_the_selected_speakers_until_terminate() {
+        let mut group = GroupChat::new();
+        group.register(&scripted_agent("writer", &["Here is a draft."]));
+        group.register(&scripted_agent("critic", &["Looks good. TERMINATE"]));
+        let selector = Arc::new(ScriptedModelClient::from_texts(&["writer", "critic"]));
+        group.set_model_client(selector.clone());
+
+        let transcript = group.run(vec![user("Write a haiku")]).await;
+        let speakers: Vec<Option<String>> = transcript.iter().map(|m| m.name.clone()).collect();
+        assert_eq!(
+            speakers,
+            vec![None, Some("writer".to_string()), Some("critic".to_string())]
+        );
+        assert_eq!(selector.remaining(), 0);
+        // the critic was chosen with the writer's draft in view
+        assert!(selector.requests()[1].contains(&transcript[1]));
+    }
+
+    #[tokio::test]
+    async fn swarm_hands_off_through_the_transfer_tool() {
+        let mut group = GroupChat::new();
+        group.register(&scripted_agent(
+            "triage",
+            &[concat!(
+                r#"<tool_call>{"name": "transfer_to_billing", "#,
+                r#""arguments": {"invoice": "42"}}</tool_call>"#
+            )],
+        ));
+        group.register(&scripted_agent("billing", &["Invoice 42 is paid."]));
+
+        let transcript = group
+            .run_swarm("triage", vec![user("Is my invoice paid?")])
+            .await;
+        assert_eq!(transcript.len(), 3);
+        assert!(matches!(
+            &transcript[1].content,
+            Some(Content::ToolCall(call)) if call.name == "transfer_to_billing"
+        ));
+        assert_eq!(transcript[2].name.as_deref(), Some("billing"));
+        assert_eq!(group.context.get("invoice").map(String::as_str), Some("42"));
+    }
+
+    fn contains_text(messages: &[Message], needle: &str) -> bool {
+        messages
+            .iter()
+            .any(|m| m.content_to_string().is_some_and(|text| text.contains(needle)))
+    }
+
+    #[tokio::test]
+    async fn direct_replies_stay_out_of_other_prompts_and_the_transcript() {
+        let critic_client = Arc::new(ScriptedModelClient::from_texts(&["Fine. TERMINATE"]));
+        let mut critic = ConversableAgent::new("critic");
+        critic.set_model_client(critic_client.clone());
+
+        let mut group = GroupChat::new();
+        group.register(&scripted_agent("writer", &["secret draft"]));
+        group.register(&critic);
+        group.register(&scripted_agent("judge", &[]));
+        group.set_reply_visibility("writer", Visibility::Direct("critic".to_string()));
+        let selector = Arc::new(ScriptedModelClient::from_texts(&["writer", "critic"]));
+        group.set_model_client(selector.clone());
+
+        let transcript = group.run(vec![user("Write a haiku")]).await;
+        assert!(contains_text(&critic_client.requests()[0], "secret draft"));
+        assert!(!contains_text(&selector.requests()[1], "secret draft"));
+        assert!(!contains_text(&group.history_for("judge"), "secret draft"));
+        assert!(!contains_text(&transcript, "secret draft"));
+        assert_eq!(transcript.len(), 2);
+        // the full record is still kept
+        assert!(contains_text(&group.messages, "secret draft"));
+    }
+
+    #[tokio::test]
+    async fn direct_replies_are_stored_as_private() {
+        use crate::message_store::SqliteMessageStore;
+        use crate::transcript_export::{export_from_store, TranscriptFormat};
+
+        let store = Arc::new(SqliteMessageStore::open_in_memory().unwrap());
+        let mut group = GroupChat::new();
+        group.register(&scripted_agent("writer", &["secret draft"]));
+        group.register(&scripted_agent("critic", &["Fine. TERMINATE"]));
+        group.set_reply_visibility("writer", Visibility::Direct("critic".to_string()));
+        group.set_model_client(Arc::new(ScriptedModelClient::from_texts(&[
+            "writer", "critic",
+        ])));
+        group.set_store(store.clone());
+        group.run(vec![user("Write a haiku")]).await;
+
+        let stored = store.load_conversation(&group.conversation_id).unwrap();
+        let visibilities: Vec<Option<&str>> = stored
+            .iter()
+            .map(|stored| stored.visibility.as_deref())
+            .collect();
+        assert_eq!(visibilities, vec![None, Some("direct:critic"), None]);
+        assert!(store.search_messages("secret", 10).unwrap().is_empty());
+        let markdown =
+            export_from_store(store.as_ref(), &group.conversation_id, TranscriptFormat::Markdown)
+                .unwrap();
+        assert!(!markdown.contains("secret draft"));
+        let window = store
+            .messages_within_budget(&group.conversation_id, 1000)
+            .unwrap();
+        assert_eq!(window.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn tool_results_keep_their_role_in_every_history() {
+        let mut group = GroupChat::new();
+        group.register(&scripted_agent("writer", &[]));
+        let result = Message::new(
+            Some(Content::Text("22 degrees".to_string())),
+            Some("get_weather".to_string()),
+            Some(Role::Tool),
+        );
+        group.post(user("How warm is it?")).await;
+        group.post(result).await;
+
+        let roles: Vec<Option<Role>> = group
+            .history_for("writer")
+            .iter()
+            .map(|message| message.role)
+            .collect();
+        assert_eq!(roles, vec![Some(Role::User), Some(Role::Tool)]);
+    }
+
+    #[tokio::test]
+    async fn context_of_a_direct_handoff_reaches_only_its_recipient() {
+        let mut group = GroupChat::new();
+        group.register(&scripted_agent(
+            "triage",
+            &[concat!(
+                r#"<tool_call>{"name": "transfer_to_billing", "#,
+                r#""arguments": {"invoice": "42"}}</tool_call>"#
+            )],
+        ));
+        group.register(&scripted_agent("billing", &["Invoice 42 is paid."]));
+        group.register(&scripted_agent("sales", &[]));
+        group.set_reply_visibility("triage", Visibility::Direct("billing".to_string()));
+
+        group
+            .run_swarm("triage", vec![user("Is my invoice paid?")])
+            .await;
+        assert!(!group.context.contains_key("invoice"));
+        let billing = group.agents["billing"].clone();
+        let sales = group.agents["sales"].clone();
+        assert!(group.swarm_system_prompt(&billing).contains(r#""invoice":"42""#));
+        assert!(!group.swarm_system_prompt(&sales).contains("invoice"));
+    }
+
+    #[test]
+    fn handoff_tools_are_valid_native_tools() {
+        let mut group = GroupChat::new();
+        group.register(&scripted_agent("triage", &[]));
+        group.register(&scripted_agent("billing", &[]));
+
+        let tools = to_native_tools(&group.handoff_tools("triage")).unwrap();
+        assert_eq!(tools.len(), 1);
+        assert_eq!(tools[0].function.name, "transfer_to_billing");
+        let parameters = tools[0].function.parameters.clone().unwrap();
+        assert_eq!(parameters["type"], "object");
+        assert_eq!(parameters["properties"]["context"]["type"], "string");
+        assert_eq!(parameters["required"], json!([]));
+    }
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn exact_names_resolve_despite_chatter_and_spelling_variants() {
+        let resolver = SpeakerResolver::default();
+        let names = names(&["code_reviewer", "writer"]);
+        assert_eq!(
+            resolver.resolve("writer", &names),
+            SpeakerMatch::Resolved("writer".to_string())
+        );
+        assert_eq!(
+            resolver.resolve("I think the Code Reviewer should go next.", &names),
+            SpeakerMatch::Resolved("code_reviewer".to_string())
+        );
+    }
+
+    #[test]
+    fn the_most_mentioned_name_wins_and_ties_are_ambiguous() {
+        let resolver = SpeakerResolver::default();
+        let names = names(&["critic", "writer"]);
+        let counts = SpeakerResolver::count_mentions("writer, then critic, then writer", &names);
+        assert_eq!(counts.get("writer"), Some(&2));
+        assert_eq!(counts.get("critic"), Some(&1));
+        assert_eq!(
+            resolver.resolve("writer, then critic, then writer", &names),
+            SpeakerMatch::Resolved("writer".to_string())
+        );
+        assert_eq!(
+            resolver.resolve("critic or writer", &names),
+            SpeakerMatch::Ambiguous(names.clone())
+        );
+    }
+
+    #[test]
+    fn a_name_inside_a_longer_name_is_not_counted_twice() {
+        let resolver = SpeakerResolver::default();
+        let names = names(&["coder", "senior_coder"]);
+        let counts = SpeakerResolver::count_mentions("senior coder", &names);
+        assert_eq!(counts.get("senior_coder"), Some(&1));
+        assert_eq!(counts.get("coder"), None);
+        assert_eq!(
+            resolver.resolve("the senior coder", &names),
+            SpeakerMatch::Resolved("senior_coder".to_string())
+        );
+        assert_eq!(
+            resolver.resolve("coder", &names),
+            SpeakerMatch::Resolved("coder".to_string())
+        );
+        // no partial words: "encoder" does not mention the coder
+        assert_eq!(resolver.resolve("encoder", &names), SpeakerMatch::NotFound);
+    }
+
+    #[test]
+    fn misspelled_names_are_matched_when_only_one_is_close() {
+        let resolver = SpeakerResolver::default();
+        let names = names(&["critic", "writer"]);
+        assert_eq!(
+            resolver.resolve("Critc", &names),
+            SpeakerMatch::Resolved("critic".to_string())
+        );
+        assert_eq!(
+            resolver.resolve("next up: writr", &names),
+            SpeakerMatch::Resolved("writer".to_string())
+        );
+        assert_eq!(resolver.resolve("nobody", &names), SpeakerMatch::NotFound);
+    }
+
+    #[test]
+    fn fallback_prefers_a_registered_default_speaker() {
+        let names = names(&["critic", "writer"]);
+        assert_eq!(
+            SpeakerResolver::new(0, Some("writer".to_string())).fallback(&names),
+            Some("writer".to_string())
+        );
+        assert_eq!(
+            SpeakerResolver::new(0, Some("editor".to_string())).fallback(&names),
+            Some("critic".to_string())
+        );
+        assert_eq!(SpeakerResolver::default().fallback(&[]), None);
+    }
+
+    #[tokio::test]
+    async fn selection_falls_back_after_the_corrections_are_used_up() {
+        let mut group = GroupChat::new();
+        group.register(&scripted_agent("writer", &[]));
+        group.register(&scripted_agent("critic", &[]));
+        group.speaker_resolver = SpeakerResolver::new(1, Some("writer".to_string()));
+        let selector = Arc::new(ScriptedModelClient::from_texts(&[
+            "either one",
+            "critic or writer",
+        ]));
+        group.set_model_client(selector.clone());
+
+        let speaker = group.select_speaker(&[user("Write a haiku")]).await;
+        assert_eq!(speaker.as_deref(), Some("writer"));
+        assert_eq!(selector.remaining(), 0);
+        // the second try saw the first answer and a correction
+        assert_eq!(selector.requests()[1].len(), 4);
     }
 }
@@ -0,0 +1,532 @@
+use crate::conversable_agent::Message;
+use crate::llama_structs::Content;
+use crate::model_client::ModelClient;
+use crate::token_counter::{
+    fit_to_budget, messages_within_budget, TokenCounter, WordCountTokenCounter,
+};
+use crate::HISTORY_COMPRESSION_TEMPLATE;
+use async_openai::types::Role;
+use lazy_static::lazy_static;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+#[derive(Clone)]
+pub struct HistoryCompression {
+    pub enabled: bool,
+    // context window of the model, in tokens
+    pub context_limit: usize,
+    // share of the space left after the reply reserve that the newest turns may keep verbatim
+    pub recent_share: f32,
+    pub summary_max_tokens: u16,
+    pub counter: Arc<dyn TokenCounter>,
+}
+
+impl Default for HistoryCompression {
+    fn default() -> Self {
+        HistoryCompression {
+            enabled: true,
+            context_limit: 8192,
+            recent_share: 0.5,
+            summary_max_tokens: 500,
+            counter: Arc::new(WordCountTokenCounter),
+        }
+    }
+}
+
+lazy_static! {
+    pub static ref HISTORY_COMPRESSION: Arc<Mutex<HistoryCompression>> =
+        Arc::new(Mutex::new(HistoryCompression::default()));
+    // Summaries already written, by the key of the history prefix they stand for.
+    static ref SUMMARIES: Mutex<HashMap<String, String>> = Mutex::new(HashMap::new());
+}
+
+// Past this many summaries the cache starts over.
+const MAX_CACHED_SUMMARIES: usize = 256;
+
+fn is_tool_result(message: &Message) -> bool {
+    matches!(message.role, Some(Role::Tool) | Some(Role::Function))
+}
+
+pub enum CompressionPlan {
+    // everything fits, send as is
+    Keep,
+    // `leading` system messages, then `older` to be summarized, then `recent` kept verbatim
+    Summarize {
+        leading: Vec<Message>,
+        older: Vec<Message>,
+        recent: Vec<Message>,
+    },
+}
+
+pub fn plan_compression(
+    messages: &[Message],
+    max_token: u16,
+    settings: &HistoryCompression,
+) -> CompressionPlan {
+    let threshold = settings.context_limit.saturating_sub(max_token as usize);
+    let counter = settings.counter.as_ref();
+    let total: usize = messages
+        .iter()
+        .map(|message| counter.count_message_tokens(message))
+        .sum();
+    if !settings.enabled || total <= threshold {
+        return CompressionPlan::Keep;
+    }
+
+    let leading_count = messages
+        .iter()
+        .take_while(|message| message.role == Some(Role::System))
+        .count();
+    let (leading, rest) = messages.split_at(leading_count);
+    let leading_tokens: usize = leading
+        .iter()
+        .map(|message| counter.count_message_tokens(message))
+        .sum();
+
+    let recent_budget =
+        (threshold.saturating_sub(leading_tokens) as f32 * settings.recent_share) as usize;
+    let mut recent = fit_to_budget(
+        rest,
+        recent_budget,
+        |message| counter.count_message_tokens(message),
+        |_| false,
+    );
+    // the newest turn always goes through, even when it alone is over the budget
+    if recent.is_empty() {
+        recent.extend(rest.last().cloned());
+    }
+    // a tool result stays next to the call it answers
+    let mut split = rest.len() - recent.len();
+    while split > 0 && is_tool_result(&rest[split]) {
+        split -= 1;
+    }
+    if split == 0 {
+        return CompressionPlan::Keep;
+    }
+
+    CompressionPlan::Summarize {
+        leading: leading.to_vec(),
+        older: rest[..split].to_vec(),
+        recent: rest[split..].to_vec(),
+    }
+}
+
+// Keys of `leading` followed by the first k older turns, for every k from 0 to older.len().
+// The leading system messages tell conversations apart, the turns after them the prefix.
+fn prefix_keys(leading: &[Message], older: &[Message]) -> Vec<String> {
+    let hex = |hasher: &Sha256| {
+        hasher
+            .clone()
+            .finalize()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect::<String>()
+    };
+    let mut hasher = Sha256::new();
+    for message in leading {
+        hasher.update(serde_json::to_vec(message).unwrap_or_default());
+        hasher.update(b"\n");
+    }
+    let mut keys = vec![hex(&hasher)];
+    for message in older {
+        hasher.update(serde_json::to_vec(message).unwrap_or_default());
+        hasher.update(b"\n");
+        keys.push(hex(&hasher));
+    }
+    keys
+}
+
+fn summary_message(summary: &str) -> Message {
+    Message::new(
+        Some(Content::Text(format!(
+            "Summary of the earlier conversation:\n{}",
+            summary.trim()
+        ))),
+        None,
+        Some(Role::System),
+    )
+}
+
+enum Compression {
+    Keep,
+    Done(Vec<Message>),
+    // `older` still has to be summarized, after the summary of the turns before it if any
+    Summarize {
+        leading: Vec<Message>,
+        previous: Option<String>,
+        older: Vec<Message>,
+        recent: Vec<Message>,
+        key: String,
+    },
+}
+
+// Reuses the summary of the longest prefix of `older` that has one: as it is when it covers
+// all of `older`, or with the turns since kept verbatim when those still fit. Otherwise only
+// the turns since are left to summarize.
+fn prepare_compression(
+    messages: &[Message],
+    max_token: u16,
+    settings: &HistoryCompression,
+) -> Compression {
+    let (leading, older, recent) = match plan_compression(messages, max_token, settings) {
+        CompressionPlan::Summarize {
+            leading,
+            older,
+            recent,
+        } => (leading, older, recent),
+        CompressionPlan::Keep => return Compression::Keep,
+    };
+    let threshold = settings.context_limit.saturating_sub(max_token as usize);
+    let counter = settings.counter.as_ref();
+    let keys = prefix_keys(&leading, &older);
+    let cached = {
+        let summaries = SUMMARIES.lock().unwrap();
+        (1..keys.len())
+            .rev()
+            .filter(|&k| k == older.len() || !is_tool_result(&older[k]))
+            .find_map(|k| summaries.get(&keys[k]).map(|summary| (k, summary.clone())))
+    };
+
+    if let Some((covered, summary)) = &cached {
+        let mut compressed = leading.clone();
+        compressed.push(summary_message(summary));
+        compressed.extend_from_slice(&older[*covered..]);
+        compressed.extend_from_slice(&recent);
+        let total: usize = compressed
+            .iter()
+            .map(|message| counter.count_message_tokens(message))
+            .sum();
+        if *covered == older.len() || total <= threshold {
+            return Compression::Done(compressed);
+        }
+    }
+
+    let (previous, start) = match cached {
+        Some((covered, summary)) => (Some(summary), covered),
+        None => (None, 0),
+    };
+    // the summary request must fit as well, so only its newest part is sent when it does not
+    let older = messages_within_budget(
+        &older[start..],
+        threshold.saturating_sub(settings.summary_max_tokens as usize),
+        counter,
+    );
+    Compression::Summarize {
+        leading,
+        previous,
+        older,
+        recent,
+        key: keys[keys.len() - 1].clone(),
+    }
+}
+
+fn transcript_line(message: &Message) -> Option<String> {
+    let speaker = match (&message.name, message.role) {
+        (Some(name), _) => name.clone(),
+        (None, Some(Role::System)) => "system".to_string(),
+        (None, Some(Role::Assistant)) => "assistant".to_string(),
+        (None, Some(Role::Tool)) | (None, Some(Role::Function)) => "tool".to_string(),
+        _ => "user".to_string(),
+    };
+    message
+        .content_to_string()
+        .map(|text| format!("{}: {}", speaker, text))
+}
+
+// Replaces the older turns of an over-long history with one system note written by the LLM,
+// unless turned off in HISTORY_COMPRESSION. Only the request is shortened: whatever is
+// persisted in a MessageStore stays untouched. A summary is written once per prefix of a
+// conversation and reused after; if summarizing fails the history is sent unchanged.
+pub async fn compress_history<C: ModelClient + ?Sized>(
+    client: &C,
+    messages: Vec<Message>,
+    max_token: u16,
+) -> Vec<Message> {
+    let settings = HISTORY_COMPRESSION.lock().unwrap().clone();
+    compress_history_with(client, messages, max_token, settings).await
+}
+
+pub async fn compress_history_with<C: ModelClient + ?Sized>(
+    client: &C,
+    messages: Vec<Message>,
+    max_token: u16,
+    settings: HistoryCompression,
+) -> Vec<Message> {
+    if !settings.enabled {
+        return messages;
+    }
+    let summary_max_tokens = settings.summary_max_tokens;
+
+    // counters may ask a server, so the counting runs on a blocking thread
+    let prepared = {
+        let messages = messages.clone();
+        tokio::task::spawn_blocking(move || prepare_compression(&messages, max_token, &settings))
+            .await
+    };
+    let (leading, previous, older, recent, key) = match prepared {
+        Ok(Compression::Summarize {
+            leading,
+            previous,
+            older,
+            recent,
+            key,
+        }) => (leading, previous, older, recent, key),
+        Ok(Compression::Done(compressed)) => return compressed,
+        Ok(Compression::Keep) => return messages,
+        Err(e) => {
+            println!("Error planning history compression: {:?}", e);
+            return messages;
+        }
+    };
+    let transcript = previous
+        .map(|summary| format!("summary of what came before: {}", summary.trim()))
+        .into_iter()
+        .chain(older.iter().filter_map(transcript_line))
+        .collect::<Vec<String>>()
+        .join("\n");
+    let prompt = {
+        let template = HISTORY_COMPRESSION_TEMPLATE.lock().unwrap();
+        template(&[&transcript])
+    };
+
+    let summary = match client
+        .complete(
+            vec![Message::new(
+                Some(Content::Text(prompt)),
+                None,
+                Some(Role::User),
+            )],
+            summary_max_tokens,
+        )
+        .await
+    {
+        Ok(reply) => match reply.content {
+            Content::Text(summary) => summary,
+            Content::ToolCall(_) => {
+                println!("History summary came back as a tool call, keeping the full history");
+                return messages;
+            }
+        },
+        Err(e) => {
+            println!(
+                "Error compressing history, keeping the full history: {:?}",
+                e
+            );
+            return messages;
+        }
+    };
+    {
+        let mut summaries = SUMMARIES.lock().unwrap();
+        if summaries.len() >= MAX_CACHED_SUMMARIES {
+            summaries.clear();
+        }
+        summaries.insert(key, summary.clone());
+    }
+
+    let mut compressed = leading;
+    compressed.push(summary_message(&summary));
+    compressed.extend(recent);
+    compressed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model_client::ScriptedModelClient;
+
+    fn message(role: Role, words: usize) -> Message {
+        said(role, "word", words)
+    }
+
+    // summaries are cached across the process, so each test talks about its own word
+    fn said(role: Role, word: &str, words: usize) -> Message {
+        Message::new(
+            Some(Content::Text(vec![word; words].join(" "))),
+            None,
+            Some(role),
+        )
+    }
+
+    #[test]
+    fn only_overflowing_history_is_split_into_older_and_recent() {
+        let settings = HistoryCompression {
+            enabled: true,
+            context_limit: 100,
+            ..HistoryCompression::default()
+        };
+        let short = vec![message(Role::System, 10), message(Role::User, 10)];
+        assert!(matches!(
+            plan_compression(&short, 20, &settings),
+            CompressionPlan::Keep
+        ));
+
+        let mut long = vec![message(Role::System, 10)];
+        for i in 0..8 {
+            long.push(message(
+                if i % 2 == 0 {
+                    Role::User
+                } else {
+                    Role::Assistant
+                },
+                10,
+            ));
+        }
+        match plan_compression(&long, 20, &settings) {
+            CompressionPlan::Summarize {
+                leading,
+                older,
+                recent,
+            } => {
+                assert_eq!(leading, long[..1]);
+                // (100 - 20 - 10) * 0.5 = 35 tokens keep the three newest turns
+                assert_eq!(recent, long[6..]);
+                assert_eq!(older, long[1..6]);
+            }
+            CompressionPlan::Keep => panic!("history over the limit was kept"),
+        }
+    }
+
+    fn long_history(word: &str) -> Vec<Message> {
+        let mut history = vec![said(Role::System, word, 10)];
+        for _ in 0..8 {
+            history.push(said(Role::User, word, 10));
+        }
+        history
+    }
+
+    fn enabled(context_limit: usize) -> HistoryCompression {
+        HistoryCompression {
+            enabled: true,
+            context_limit,
+            summary_max_tokens: 20,
+            ..HistoryCompression::default()
+        }
+    }
+
+    #[test]
+    fn compression_is_on_by_default_against_the_context_limit() {
+        let settings = HistoryCompression::default();
+        assert!(settings.enabled);
+        assert!(matches!(
+            plan_compression(&long_history("fits"), 20, &settings),
+            CompressionPlan::Keep
+        ));
+
+        let mut overflowing = vec![message(Role::System, 10)];
+        for _ in 0..20 {
+            overflowing.push(message(Role::User, 500));
+        }
+        assert!(matches!(
+            plan_compression(&overflowing, 500, &settings),
+            CompressionPlan::Summarize { .. }
+        ));
+    }
+
+    #[test]
+    fn a_tool_result_is_never_split_from_its_call() {
+        let call = Message::new(
+            Some(Content::ToolCall(crate::llama_structs::ToolCall {
+                name: "search".to_string(),
+                arguments: Some(HashMap::from([(
+                    "query".to_string(),
+                    vec!["term"; 20].join(" "),
+                )])),
+                id: Some("call_1".to_string()),
+            })),
+            None,
+            Some(Role::Assistant),
+        );
+        let mut history = vec![message(Role::System, 10)];
+        for _ in 0..5 {
+            history.push(message(Role::User, 10));
+        }
+        history.push(call);
+        history.push(message(Role::Tool, 10));
+        history.push(message(Role::User, 10));
+        history.push(message(Role::Assistant, 10));
+
+        // (100 - 20 - 10) * 0.5 = 35 tokens would keep the result but not its call
+        match plan_compression(&history, 20, &enabled(100)) {
+            CompressionPlan::Summarize { older, recent, .. } => {
+                assert_eq!(recent, history[6..]);
+                assert_eq!(older, history[1..6]);
+            }
+            CompressionPlan::Keep => panic!("history over the limit was kept"),
+        }
+    }
+
+    #[tokio::test]
+    async fn older_turns_are_replaced_by_the_summary() {
+        let client = ScriptedModelClient::from_texts(&["  they said word a lot  "]);
+        let history = long_history("word");
+        let compressed = compress_history_with(&client, history.clone(), 20, enabled(100)).await;
+
+        assert_eq!(compressed.len(), 5);
+        assert_eq!(compressed[0], history[0]);
+        assert_eq!(
+            compressed[1].content,
+            Some(Content::Text(
+                "Summary of the earlier conversation:\nthey said word a lot".to_string()
+            ))
+        );
+        assert_eq!(compressed[2..], history[6..]);
+        // the summary prompt carries the five older turns
+        let request = &client.requests()[0];
+        assert_eq!(
+            request[0]
+                .content_to_string()
+                .unwrap()
+                .matches("user: word")
+                .count(),
+            5
+        );
+    }
+
+    #[tokio::test]
+    async fn a_failed_summary_keeps_the_full_history() {
+        let client = ScriptedModelClient::from_texts(&[]);
+        let history = long_history("failing");
+        let compressed = compress_history_with(&client, history.clone(), 20, enabled(100)).await;
+        assert_eq!(compressed, history);
+
+        let disabled = HistoryCompression {
+            enabled: false,
+            context_limit: 100,
+            ..HistoryCompression::default()
+        };
+        let untouched = compress_history_with(&client, history.clone(), 20, disabled).await;
+        assert_eq!(untouched, history);
+        assert_eq!(client.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn a_summary_is_written_once_per_prefix() {
+        let client = ScriptedModelClient::from_texts(&["cached summary", "rolled summary"]);
+        let mut history = long_history("again");
+        let first = compress_history_with(&client, history.clone(), 20, enabled(100)).await;
+        let second = compress_history_with(&client, history.clone(), 20, enabled(100)).await;
+        assert_eq!(second, first);
+        assert_eq!(client.requests().len(), 1);
+
+        // one more turn: the cached summary plus the turn it does not cover still fit
+        history.push(said(Role::User, "again", 10));
+        let third = compress_history_with(&client, history.clone(), 20, enabled(100)).await;
+        assert_eq!(third[1], first[1]);
+        assert_eq!(third[2..], history[6..]);
+        assert_eq!(client.requests().len(), 1);
+
+        // more turns than fit: only those since the cached summary are summarized, after it
+        for _ in 0..3 {
+            history.push(said(Role::User, "again", 10));
+        }
+        let fourth = compress_history_with(&client, history.clone(), 20, enabled(100)).await;
+        assert_eq!(
+            fourth[1].content_to_string().unwrap(),
+            "Summary of the earlier conversation:\nrolled summary"
+        );
+        let request = client.requests()[1][0].content_to_string().unwrap();
+        assert!(request.contains("summary of what came before: cached summary"));
+        assert_eq!(request.matches("user: again").count(), 4);
+    }
+}
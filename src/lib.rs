@@ -1,12 +1,23 @@
 // pub mod conversable_agent;
 // pub mod groupchat;
+pub mod audit_log;
 pub mod conversable_agent;
 pub mod exec_python;
+pub mod fan_out;
+pub mod fine_tuning;
 pub mod groupchat;
+pub mod history_compression;
 pub mod llama_structs;
 pub mod llm_llama_local;
 pub mod webscraper_hook;
 pub mod message_store;
+pub mod model_client;
+pub mod redaction;
+pub mod request_policy;
+pub mod response_cache;
+pub mod token_counter;
+pub mod transcript_export;
+pub mod vector_memory;
 // pub mod tool_call_actuators;
 use lazy_static::lazy_static;
 use std::sync::{Arc, Mutex};
@@ -90,6 +101,41 @@ pub static ref FUNCTON_CALL_SYSTEM_PROMPT: String = r#"<|im_start|>system You ar
     pub static ref ITERATE_CODE_RETRY_TEMPLATE: Arc<Mutex<FormatterFn>> = Arc::new(Mutex::new(Box::new(|args: &[&str]| {
         format!("Error: {}\nNow let's retry: take care not to repeat previous errors! Try to adopt different approaches.", args[0])
     })));
+
+    pub static ref SPEAKER_SELECTION_TEMPLATE: Arc<Mutex<FormatterFn>> = Arc::new(Mutex::new(Box::new(|args: &[&str]| {
+        format!("You are in a role play game. The following roles are available:\n{}\nRead the conversation, then select the next role from {} to play. Only return the role name.", args[0], args[1])
+    })));
+
+    pub static ref GROUP_CHAT_SUMMARY_TEMPLATE: Arc<Mutex<FormatterFn>> = Arc::new(Mutex::new(Box::new(|args: &[&str]| {
+        format!("You are {}, reporting the outcome of an internal team discussion. Summarize the conversation below into one concise answer to the original request, keeping concrete results and dropping the back-and-forth:\n{}", args[0], args[1])
+    })));
+
+    pub static ref HANDOFF_TOOLS_TEMPLATE: Arc<Mutex<FormatterFn>> = Arc::new(Mutex::new(Box::new(|args: &[&str]| {
+        format!(r#"You can hand the conversation over to another agent when it is better suited to continue. You are provided with function signatures within <tools></tools> XML tags: <tools>
+{}
+</tools>
+To hand over, return a json object with function name and arguments within <tool_call></tool_call> XML tags as follows:
+<tool_call>
+{{"arguments": {{"context": <what the next agent needs to know>}}, "name": <function-name>}}
+</tool_call>
+Otherwise answer normally. Shared context so far: {}"#, args[0], args[1])
+    })));
+
+    pub static ref FAN_OUT_SYNTHESIS_TEMPLATE: Arc<Mutex<FormatterFn>> = Arc::new(Mutex::new(Box::new(|args: &[&str]| {
+        format!("Several agents answered the same request independently.\nRequest: {}\nAnswers:\n{}\nCombine them into one answer that keeps the best points and resolves contradictions.", args[0], args[1])
+    })));
+
+    pub static ref HISTORY_COMPRESSION_TEMPLATE: Arc<Mutex<FormatterFn>> = Arc::new(Mutex::new(Box::new(|args: &[&str]| {
+        format!("Summarize the earlier part of a conversation below so that it can replace it. Keep the facts, decisions, results of code and tool runs, and open questions; drop greetings and repetition. Reply with the summary only.\n{}", args[0])
+    })));
+
+    pub static ref RECALLED_MEMORY_TEMPLATE: Arc<Mutex<FormatterFn>> = Arc::new(Mutex::new(Box::new(|args: &[&str]| {
+        format!("Earlier in this work, outside of the messages below, the following came up. Use it if it is relevant:\n{}", args[0])
+    })));
+
+    pub static ref SPEAKER_CORRECTION_TEMPLATE: Arc<Mutex<FormatterFn>> = Arc::new(Mutex::new(Box::new(|args: &[&str]| {
+        format!("Your answer \"{}\" did not name exactly one role. Reply with exactly one name from {} and nothing else.", args[0], args[1])
+    })));
 }
 
 fn use_function() {
@@ -1,6 +1,7 @@
 use crate::FUNCTON_CALL_SYSTEM_PROMPT;
 use async_openai::types::{CompletionUsage, CreateChatCompletionResponse, Role};
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 use std::collections::HashMap;
 
 use crate::{llm_llama_local::chat_inner_async, webscraper_hook::get_webpage_text};
@@ -9,6 +10,35 @@ use crate::{llm_llama_local::chat_inner_async, webscraper_hook::get_webpage_text
 pub struct ToolCall {
     pub name: String,
     pub arguments: Option<HashMap<String, String>>,
+    // set when the call came in through native tool calling
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+}
+
+// The arguments as the JSON object string of OpenAI's function calling.
+pub fn tool_call_arguments_json(tool_call: &ToolCall) -> String {
+    let arguments: serde_json::Map<String, Value> = tool_call
+        .arguments
+        .iter()
+        .flatten()
+        .map(|(key, value)| (key.clone(), Value::String(value.clone())))
+        .collect();
+    Value::Object(arguments).to_string()
+}
+
+// Non-string values are kept as their JSON text.
+pub fn parse_tool_arguments(arguments: &str) -> Option<HashMap<String, String>> {
+    match serde_json::from_str::<Value>(arguments) {
+        Ok(Value::Object(map)) => Some(
+            map.into_iter()
+                .map(|(key, value)| match value {
+                    Value::String(text) => (key, text),
+                    other => (key, other.to_string()),
+                })
+                .collect(),
+        ),
+        _ => None,
+    }
 }
 
 #[allow(non_snake_case)]
@@ -39,29 +69,82 @@ fn extract_json_from_xml_like(xml_like_data: &str) -> Option<String> {
     }
 }
 
+// Text from any backend: a lone <tool_call> block becomes a tool call, the rest stays text.
+// The {"name": .., "arguments": {..}} of a <tool_call> block. The arguments go through
+// parse_tool_arguments like native ones, so numbers, booleans and nested objects are kept;
+// an arguments string holding JSON is read the same way.
+fn tool_call_from_json(json_str: &str) -> Option<ToolCall> {
+    let value: Value = serde_json::from_str(json_str).ok()?;
+    let name = value.get("name")?.as_str()?.to_string();
+    let arguments = match value.get("arguments") {
+        None | Some(Value::Null) => None,
+        Some(Value::String(arguments)) => parse_tool_arguments(arguments),
+        Some(arguments) => parse_tool_arguments(&arguments.to_string()),
+    };
+    Some(ToolCall {
+        name,
+        arguments,
+        id: None,
+    })
+}
+
+pub fn llama_response_from_text(
+    text: String,
+    role: Role,
+    usage: CompletionUsage,
+) -> LlamaResponseMessage {
+    let tool_call = extract_json_from_xml_like(&text)
+        .and_then(|json_str| tool_call_from_json(&json_str));
+    let content = match tool_call {
+        Some(tool_call) => Content::ToolCall(tool_call),
+        None => Content::Text(text),
+    };
+    LlamaResponseMessage {
+        content,
+        role,
+        usage,
+    }
+}
+
 pub fn output_llama_response(
     res_obj: CreateChatCompletionResponse,
-) -> Option<LlamaResponseMessage> {
-    let usage = res_obj.clone().usage.unwrap();
-    let msg_obj = res_obj.clone().choices[0].message.clone();
-    let role = msg_obj.clone().role;
-    if let Some(data) = msg_obj.content {
-        if let Some(json_str) = extract_json_from_xml_like(&data) {
-            let tool_call: ToolCall = serde_json::from_str(&json_str).unwrap();
-            return Some(LlamaResponseMessage {
-                content: Content::ToolCall(tool_call),
-                role: role,
-                usage: usage,
-            });
-        } else {
-            return Some(LlamaResponseMessage {
-                content: Content::Text(data.to_owned()),
-                role: role,
-                usage: usage,
-            });
+) -> anyhow::Result<LlamaResponseMessage> {
+    // some local servers leave the usage out
+    let usage = res_obj.usage.unwrap_or(CompletionUsage {
+        prompt_tokens: 0,
+        completion_tokens: 0,
+        total_tokens: 0,
+    });
+    let Some(choice) = res_obj.choices.into_iter().next() else {
+        return Err(anyhow::anyhow!("The reply has no choices"));
+    };
+    let msg_obj = choice.message;
+    let role = msg_obj.role;
+    // Content holds a single call, so a turn with several of them is refused whole
+    match msg_obj.tool_calls.as_deref() {
+        Some([native]) => {
+            return Ok(LlamaResponseMessage {
+                content: Content::ToolCall(ToolCall {
+                    name: native.function.name.clone(),
+                    arguments: parse_tool_arguments(&native.function.arguments),
+                    id: Some(native.id.clone()),
+                }),
+                role,
+                usage,
+            })
+        }
+        Some(calls) if calls.len() > 1 => {
+            return Err(anyhow::anyhow!(
+                "The model made {} tool calls at once, only one is supported",
+                calls.len()
+            ))
         }
+        _ => {}
     }
-    None
+    msg_obj
+        .content
+        .map(|data| llama_response_from_text(data, role, usage))
+        .ok_or_else(|| anyhow::anyhow!("Empty output in Llama format"))
 }
 
 pub async fn fire_tool_call(
@@ -1,28 +1,45 @@
 use crate::conversable_agent::Message;
-use crate::llama_structs::{output_llama_response, Content, LlamaResponseMessage};
+use crate::model_client::{ModelClient, OpenAiCompatibleClient};
+use crate::llama_structs::{
+    llama_response_from_text, output_llama_response, parse_tool_arguments,
+    tool_call_arguments_json, Content, LlamaResponseMessage, ToolCall,
+};
+use crate::request_policy::{is_retryable_status, send_with_policy, AttemptError, RequestPolicy};
 use async_openai::{
     config::Config,
     types::{
+        ChatCompletionMessageToolCall,
         ChatCompletionRequestAssistantMessage,
         ChatCompletionRequestMessage,
         // ChatCompletionFunctionsArgs,
         ChatCompletionRequestSystemMessage,
         ChatCompletionRequestSystemMessageArgs,
+        ChatCompletionRequestToolMessage,
         ChatCompletionRequestUserMessage,
         ChatCompletionRequestUserMessageArgs,
         ChatCompletionRequestUserMessageContent,
+        ChatCompletionTool,
+        ChatCompletionToolType,
         // ChatCompletionTool, ChatCompletionToolArgs, ChatCompletionToolType,
+        CompletionUsage,
+        CreateChatCompletionRequest,
         CreateChatCompletionRequestArgs,
         CreateChatCompletionResponse,
+        FunctionCall,
         Role,
     },
-    Client as OpenAIClient,
 };
 use dotenv;
-use reqwest::header::{HeaderMap, HeaderValue, CONTENT_TYPE, USER_AGENT};
+use futures::stream::BoxStream;
+use futures::{Stream, StreamExt};
+use lazy_static::lazy_static;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, CONTENT_TYPE, USER_AGENT};
 use secrecy::Secret;
 use serde::Deserialize;
-use std::collections::HashMap;
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 #[derive(Clone, Debug)]
 pub struct LocalServiceProviderConfig {
@@ -57,30 +74,163 @@ impl Config for LocalServiceProviderConfig {
     }
 }
 
+impl LocalServiceProviderConfig {
+    pub fn new(api_base: &str, api_key: &str) -> Self {
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        headers.insert(USER_AGENT, HeaderValue::from_static("MyClient/1.0.0"));
+
+        LocalServiceProviderConfig {
+            api_base: api_base.trim_end_matches('/').to_string(),
+            headers,
+            api_key: Secret::new(api_key.to_string()),
+            query: HashMap::new(),
+        }
+    }
+}
+
+pub const DEFAULT_API_BASE: &str = "http://127.0.0.1:8080/v1";
+pub const DEFAULT_MODEL: &str = "Hermes-2-Pro-Llama-3-8B";
+
+// One model server: where it is, what to send it, and which model to ask for.
+#[derive(Clone, Debug)]
+pub struct LlmClientConfig {
+    pub provider: LocalServiceProviderConfig,
+    pub model: String,
+    // Send tools in the request's `tools` field and tool calls in the history as `tool_calls`,
+    // for servers with function calling of their own (vLLM, llama.cpp with --jinja). Off, tool
+    // calls travel as <tool_call> text that the prompt asks for.
+    pub native_tools: bool,
+    pub request_policy: RequestPolicy,
+}
+
+impl LlmClientConfig {
+    pub fn new(api_base: &str, model: &str, api_key: &str) -> Self {
+        LlmClientConfig {
+            provider: LocalServiceProviderConfig::new(api_base, api_key),
+            model: model.to_string(),
+            native_tools: false,
+            request_policy: RequestPolicy::default(),
+        }
+    }
+
+    // LLAMA_API_BASE and LLAMA_MODEL fall back to the local llama.cpp defaults;
+    // LLAMA_API_KEY has to be set.
+    pub fn from_env() -> anyhow::Result<Self> {
+        let api_key = std::env::var("LLAMA_API_KEY")
+            .map_err(|_| anyhow::anyhow!("LLAMA_API_KEY is not set"))?;
+        let api_base =
+            std::env::var("LLAMA_API_BASE").unwrap_or_else(|_| DEFAULT_API_BASE.to_string());
+        let model = std::env::var("LLAMA_MODEL").unwrap_or_else(|_| DEFAULT_MODEL.to_string());
+        Ok(Self::new(&api_base, &model, &api_key))
+    }
+
+    pub fn with_header(mut self, name: &str, value: &str) -> anyhow::Result<Self> {
+        self.provider.headers.insert(
+            HeaderName::from_bytes(name.as_bytes())?,
+            HeaderValue::from_str(value)?,
+        );
+        Ok(self)
+    }
+
+    pub fn with_request_policy(mut self, request_policy: RequestPolicy) -> Self {
+        self.request_policy = request_policy;
+        self
+    }
+
+    pub fn with_native_tools(mut self) -> Self {
+        self.native_tools = true;
+        self
+    }
+
+    pub fn with_query(mut self, name: &str, value: &str) -> Self {
+        self.provider
+            .query
+            .insert(name.to_string(), value.to_string());
+        self
+    }
+}
+
+lazy_static! {
+    // Used by the functions without an explicit config; None reads the environment.
+    pub static ref DEFAULT_LLM_CONFIG: Arc<Mutex<Option<LlmClientConfig>>> = Arc::new(Mutex::new(None));
+}
+
+lazy_static! {
+    static ref HTTP_CLIENT: reqwest::Client = reqwest::Client::new();
+}
+
+// Posts the request itself rather than through async-openai's `create`, which hides the
+// status code that decides whether a failure is worth retrying.
+pub async fn post_chat_completion(
+    config: &LlmClientConfig,
+    request: &CreateChatCompletionRequest,
+) -> anyhow::Result<CreateChatCompletionResponse> {
+    let provider = &config.provider;
+    let url = provider.url("/chat/completions");
+    let body = serde_json::to_vec(request)?;
+    let (url, body) = (&url, &body);
+
+    send_with_policy(&config.request_policy, provider.api_base(), || async move {
+        let response = HTTP_CLIENT
+            .post(url)
+            .query(&provider.query())
+            .headers(provider.headers())
+            .body(body.clone())
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            return Err(status_error(url, response).await);
+        }
+        let bytes = response.bytes().await?;
+        serde_json::from_slice(&bytes).map_err(|e| {
+            AttemptError::permanent(anyhow::anyhow!("Unexpected reply from {}: {}", url, e))
+        })
+    })
+    .await
+}
+
+// A reply with a failed status, retryable or not depending on the code.
+async fn status_error(url: &str, response: reqwest::Response) -> AttemptError {
+    let status = response.status();
+    let body = response.text().await.unwrap_or_default();
+    let error = anyhow::anyhow!("{} answered {}: {}", url, status, body);
+    match is_retryable_status(status.as_u16()) {
+        true => AttemptError::retryable(error),
+        false => AttemptError::permanent(error),
+    }
+}
+
+pub fn set_default_llm_config(config: LlmClientConfig) {
+    *DEFAULT_LLM_CONFIG.lock().unwrap() = Some(config);
+}
+
+pub fn default_llm_config() -> anyhow::Result<LlmClientConfig> {
+    match DEFAULT_LLM_CONFIG.lock().unwrap().clone() {
+        Some(config) => Ok(config),
+        None => LlmClientConfig::from_env(),
+    }
+}
+
 pub async fn chat_inner_async(
     system_prompt: &str,
     user_input: &str,
     max_token: u16,
 ) -> anyhow::Result<CreateChatCompletionResponse> {
-    let mut headers = HeaderMap::new();
-    let api_key = std::env::var("LLAMA_API_KEY").expect("LLAMA_API_KEY must be set");
-    headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
-    headers.insert(USER_AGENT, HeaderValue::from_static("MyClient/1.0.0"));
-    let config = LocalServiceProviderConfig {
-        api_base: String::from("http://127.0.0.1:8080/v1"),
-        headers: headers,
-        api_key: Secret::new(api_key),
-        query: HashMap::new(),
-    };
+    chat_inner_async_with(&default_llm_config()?, system_prompt, user_input, max_token).await
+}
 
+pub async fn chat_inner_async_with(
+    config: &LlmClientConfig,
+    system_prompt: &str,
+    user_input: &str,
+    max_token: u16,
+) -> anyhow::Result<CreateChatCompletionResponse> {
     // stop: ['</s>', '[/INST]'],
-    let model = "Hermes-2-Pro-Llama-3-8B";
-    let client = OpenAIClient::with_config(config);
     let messages = vec![
         ChatCompletionRequestSystemMessageArgs::default()
             .content(system_prompt)
-            .build()
-            .expect("Failed to build system message")
+            .build()?
             .into(),
         ChatCompletionRequestUserMessageArgs::default()
             .content(user_input)
@@ -89,11 +239,11 @@ pub async fn chat_inner_async(
     ];
     let request = CreateChatCompletionRequestArgs::default()
         .max_tokens(max_token)
-        .model(model)
+        .model(config.model.clone())
         .messages(messages)
         .build()?;
 
-    let chat = match client.chat().create(request).await {
+    let chat = match post_chat_completion(config, &request).await {
         Ok(chat) => chat,
         Err(_e) => {
             println!("Error getting response from OpenAI: {:?}", _e);
@@ -113,9 +263,8 @@ impl Message {
                 tool_call.name,
                 tool_call
                     .arguments
-                    .as_ref()
-                    .unwrap()
-                    .into_iter()
+                    .iter()
+                    .flatten()
                     .map(|(arg, val)| format!("{:?}: {:?}", arg, val))
                     .collect::<Vec<String>>()
                     .join(", ")
@@ -125,9 +274,11 @@ impl Message {
     }
 }
 
-impl From<Message> for ChatCompletionRequestMessage {
-    fn from(message: Message) -> ChatCompletionRequestMessage {
-        match message.role {
+impl TryFrom<Message> for ChatCompletionRequestMessage {
+    type Error = anyhow::Error;
+
+    fn try_from(message: Message) -> anyhow::Result<ChatCompletionRequestMessage> {
+        let converted = match message.role {
             Some(Role::System) => {
                 ChatCompletionRequestMessage::System(ChatCompletionRequestSystemMessage {
                     content: message.content_to_string().unwrap_or("empty".to_string()),
@@ -163,53 +314,406 @@ impl From<Message> for ChatCompletionRequestMessage {
                 })
             }
 
-            _ => panic!("Message role must be specified"),
+            None => return Err(anyhow::anyhow!("Message role must be specified")),
+        };
+        Ok(converted)
+    }
+}
+
+fn native_tool_call(tool_call: &ToolCall, id: String) -> ChatCompletionMessageToolCall {
+    ChatCompletionMessageToolCall {
+        id,
+        r#type: ChatCompletionToolType::Function,
+        function: FunctionCall {
+            name: tool_call.name.clone(),
+            arguments: tool_call_arguments_json(tool_call),
+        },
+    }
+}
+
+// With native tools, tool calls become `tool_calls` of an assistant message and each result a
+// tool message answering the latest open call of the same name. Calls without an id get one
+// from their position; results that answer nothing are converted as before.
+pub fn to_request_messages(
+    messages: Vec<Message>,
+    native_tools: bool,
+) -> anyhow::Result<Vec<ChatCompletionRequestMessage>> {
+    if !native_tools {
+        return messages
+            .into_iter()
+            .map(ChatCompletionRequestMessage::try_from)
+            .collect();
+    }
+
+    let mut open_calls: Vec<(String, String)> = Vec::new();
+    let mut converted = Vec::with_capacity(messages.len());
+    for (index, message) in messages.into_iter().enumerate() {
+        match (&message.content, message.role) {
+            (Some(Content::ToolCall(tool_call)), Some(Role::Assistant)) => {
+                let id = tool_call
+                    .id
+                    .clone()
+                    .unwrap_or_else(|| format!("call_{}", index));
+                open_calls.push((id.clone(), tool_call.name.clone()));
+                converted.push(ChatCompletionRequestMessage::Assistant(
+                    ChatCompletionRequestAssistantMessage {
+                        content: None,
+                        role: Role::Assistant,
+                        name: message.name.clone(),
+                        tool_calls: Some(vec![native_tool_call(tool_call, id)]),
+                        ..Default::default()
+                    },
+                ));
+            }
+            (_, Some(Role::Tool)) | (_, Some(Role::Function)) => {
+                let position = open_calls
+                    .iter()
+                    .rposition(|(_, name)| Some(name) == message.name.as_ref())
+                    .or_else(|| open_calls.len().checked_sub(1));
+                match position {
+                    Some(position) => {
+                        let (tool_call_id, _) = open_calls.remove(position);
+                        converted.push(ChatCompletionRequestMessage::Tool(
+                            ChatCompletionRequestToolMessage {
+                                role: Role::Tool,
+                                content: message.content_to_string().unwrap_or_default(),
+                                tool_call_id,
+                            },
+                        ));
+                    }
+                    None => converted.push(ChatCompletionRequestMessage::try_from(message)?),
+                }
+            }
+            _ => converted.push(ChatCompletionRequestMessage::try_from(message)?),
         }
     }
+    Ok(converted)
+}
+
+// Accepts OpenAI tool definitions as well as bare function signatures like the handoff tools.
+pub fn to_native_tools(tools: &[Value]) -> anyhow::Result<Vec<ChatCompletionTool>> {
+    tools
+        .iter()
+        .map(|tool| {
+            let tool = match tool.get("function") {
+                Some(_) => tool.clone(),
+                None => json!({ "type": "function", "function": tool }),
+            };
+            serde_json::from_value(tool)
+                .map_err(|e| anyhow::anyhow!("Invalid tool definition: {}", e))
+        })
+        .collect()
 }
 
 pub async fn chat_inner_async_llama(
     messages: Vec<Message>,
     max_token: u16,
 ) -> anyhow::Result<LlamaResponseMessage> {
-    let mut headers = HeaderMap::new();
-    let api_key = std::env::var("LLAMA_API_KEY").expect("LLAMA_API_KEY must be set");
-    headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
-    headers.insert(USER_AGENT, HeaderValue::from_static("MyClient/1.0.0"));
-    let config = LocalServiceProviderConfig {
-        api_base: String::from("http://127.0.0.1:8080/v1"),
-        headers: headers,
-        api_key: Secret::new(api_key),
-        query: HashMap::new(),
-    };
+    chat_inner_async_llama_with(&default_llm_config()?, messages, max_token).await
+}
+
+pub async fn chat_inner_async_llama_with(
+    config: &LlmClientConfig,
+    messages: Vec<Message>,
+    max_token: u16,
+) -> anyhow::Result<LlamaResponseMessage> {
+    OpenAiCompatibleClient::new(config.clone())
+        .chat(messages, max_token)
+        .await
+}
 
+// Sends the messages as they are; used by the history compression itself.
+pub async fn chat_inner_async_llama_uncompressed(
+    config: &LlmClientConfig,
+    messages: Vec<Message>,
+    max_token: u16,
+) -> anyhow::Result<LlamaResponseMessage> {
+    chat_inner_async_llama_tools(config, messages, &[], max_token).await
+}
+
+// Like chat_inner_async_llama_uncompressed; the tools only go into the request when the
+// config has native_tools set.
+pub async fn chat_inner_async_llama_tools(
+    config: &LlmClientConfig,
+    messages: Vec<Message>,
+    tools: &[Value],
+    max_token: u16,
+) -> anyhow::Result<LlamaResponseMessage> {
     // stop: ['</s>', '[/INST]'],
-    let model = "Hermes-2-Pro-Llama-3-8B";
-    let client = OpenAIClient::with_config(config);
+    let messages = to_request_messages(messages, config.native_tools)?;
 
-    let messages: Vec<ChatCompletionRequestMessage> = messages
-        .into_iter()
-        .map(ChatCompletionRequestMessage::from)
-        .collect();
+    let mut request = CreateChatCompletionRequestArgs::default();
+    request
+        .max_tokens(max_token)
+        .model(config.model.clone())
+        .messages(messages);
+    if config.native_tools && !tools.is_empty() {
+        request.tools(to_native_tools(tools)?);
+    }
+    let request = request.build()?;
 
-    let request = CreateChatCompletionRequestArgs::default()
+    match post_chat_completion(config, &request).await {
+        Ok(chat) => output_llama_response(chat),
+        Err(_e) => {
+            println!("Error getting response from OpenAI: {:?}", _e);
+            Err(anyhow::anyhow!("Failed to get reply from OpenAI: {:?}", _e))
+        }
+    }
+}
+
+// Receives each piece of a streamed answer as it arrives.
+pub type OnDelta<'a> = dyn FnMut(&str) + Send + 'a;
+
+// The same request as chat_inner_async_llama_uncompressed, streamed: every piece of text is
+// handed to `on_delta` as it arrives and the whole answer is returned at the end.
+pub async fn chat_inner_async_llama_stream(
+    config: &LlmClientConfig,
+    messages: Vec<Message>,
+    max_token: u16,
+    on_delta: &mut OnDelta<'_>,
+) -> anyhow::Result<LlamaResponseMessage> {
+    chat_inner_async_llama_stream_tools(config, messages, &[], max_token, on_delta).await
+}
+
+// Streamed like chat_inner_async_llama_stream, with tools as in chat_inner_async_llama_tools.
+// The server is asked to finish with a usage chunk (`stream_options.include_usage`).
+pub async fn chat_inner_async_llama_stream_tools(
+    config: &LlmClientConfig,
+    messages: Vec<Message>,
+    tools: &[Value],
+    max_token: u16,
+    on_delta: &mut OnDelta<'_>,
+) -> anyhow::Result<LlamaResponseMessage> {
+    let messages = to_request_messages(messages, config.native_tools)?;
+
+    let mut request = CreateChatCompletionRequestArgs::default();
+    request
         .max_tokens(max_token)
-        .model(model)
+        .model(config.model.clone())
         .messages(messages)
-        .build()?;
+        .stream(true);
+    if config.native_tools && !tools.is_empty() {
+        request.tools(to_native_tools(tools)?);
+    }
+    // async-openai 0.21 has no field for the stream options
+    let mut body = serde_json::to_value(request.build()?)?;
+    body["stream_options"] = json!({ "include_usage": true });
+    let body = serde_json::to_vec(&body)?;
 
-    match client.chat().create(request).await {
-        Ok(chat) => {
-            if let Some(out) = output_llama_response(chat) {
-                Ok(out)
-            } else {
-                Err(anyhow::anyhow!("Empty output in Llama format"))
-            }
+    // Retried only until the server accepts the request: once text has been handed on,
+    // starting over would repeat it.
+    let provider = &config.provider;
+    let url = provider.url("/chat/completions");
+    let (url, body) = (&url, &body);
+    let opened = send_with_policy(&config.request_policy, provider.api_base(), || async move {
+        let response = HTTP_CLIENT
+            .post(url)
+            .query(&provider.query())
+            .headers(provider.headers())
+            .body(body.clone())
+            .send()
+            .await?;
+        match response.status().is_success() {
+            true => Ok(response),
+            false => Err(status_error(url, response).await),
         }
+    })
+    .await;
+    let response = match opened {
+        Ok(response) => response,
         Err(_e) => {
             println!("Error getting response from OpenAI: {:?}", _e);
-            Err(anyhow::anyhow!("Failed to get reply from OpenAI: {:?}", _e))
+            return Err(anyhow::anyhow!("Failed to get reply from OpenAI: {:?}", _e));
+        }
+    };
+
+    collect_llama_stream(
+        stream_chunks(response),
+        config.request_policy.timeout,
+        on_delta,
+    )
+    .await
+}
+
+// What is read from one chunk of a streamed chat completion. The chunk type of async-openai
+// 0.21 has no usage, and servers differ in which delta fields they leave out.
+#[derive(Deserialize, Debug, Default)]
+pub struct StreamChunk {
+    #[serde(default)]
+    pub choices: Vec<StreamChoice>,
+    pub usage: Option<CompletionUsage>,
+    pub error: Option<Value>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct StreamChoice {
+    #[serde(default)]
+    pub index: u32,
+    #[serde(default)]
+    pub delta: StreamDelta,
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct StreamDelta {
+    pub role: Option<Role>,
+    pub content: Option<String>,
+    pub tool_calls: Option<Vec<ToolCallDelta>>,
+}
+
+// A piece of a native tool call; the pieces with the same index make up one call.
+#[derive(Deserialize, Debug, Default)]
+pub struct ToolCallDelta {
+    #[serde(default)]
+    pub index: u32,
+    pub id: Option<String>,
+    pub function: Option<FunctionDelta>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct FunctionDelta {
+    pub name: Option<String>,
+    pub arguments: Option<String>,
+}
+
+// Splits a server-sent events body into the data of its events, whatever way the bytes
+// arrive.
+#[derive(Default)]
+pub struct SseReader {
+    buffer: Vec<u8>,
+    data: Vec<String>,
+}
+
+impl SseReader {
+    pub fn push(&mut self, bytes: &[u8]) -> Vec<String> {
+        self.buffer.extend_from_slice(bytes);
+        let mut events = Vec::new();
+        while let Some(end) = self.buffer.iter().position(|byte| *byte == b'\n') {
+            let line: Vec<u8> = self.buffer.drain(..=end).collect();
+            let line = String::from_utf8_lossy(&line);
+            let line = line.trim_end_matches(['\n', '\r']);
+            if line.is_empty() {
+                if !self.data.is_empty() {
+                    events.push(self.data.join("\n"));
+                    self.data.clear();
+                }
+            } else if let Some(data) = line.strip_prefix("data:") {
+                self.data.push(data.strip_prefix(' ').unwrap_or(data).to_string());
+            }
+        }
+        events
+    }
+
+    // The end of the body also ends an event that was not closed by a blank line.
+    pub fn finish(&mut self) -> Vec<String> {
+        self.push(b"\n\n")
+    }
+}
+
+// The chunks of a streamed reply, up to `data: [DONE]` or the end of the body.
+fn stream_chunks(response: reqwest::Response) -> BoxStream<'static, anyhow::Result<StreamChunk>> {
+    let state = (Some(response), SseReader::default(), VecDeque::new());
+    futures::stream::unfold(state, |(mut response, mut reader, mut pending)| async move {
+        loop {
+            if let Some(data) = pending.pop_front() {
+                let data: String = data;
+                if data.trim() == "[DONE]" {
+                    return None;
+                }
+                let chunk = serde_json::from_str(&data)
+                    .map_err(|e| anyhow::anyhow!("Unexpected stream chunk {}: {}", data, e));
+                return Some((chunk, (response, reader, pending)));
+            }
+            match response.as_mut()?.chunk().await {
+                Ok(Some(bytes)) => pending.extend(reader.push(&bytes)),
+                Ok(None) => {
+                    response = None;
+                    pending.extend(reader.finish());
+                }
+                Err(e) => return Some((Err(e.into()), (None, reader, pending))),
+            }
         }
+    })
+    .boxed()
+}
+
+// Joins the deltas of the first choice; a <tool_call> block can only be recognised once the
+// text is complete, native tool calls are put together from their pieces. The usage is the
+// one the server reports in its last chunk, all zero when it reports none. A stream that
+// goes quiet for longer than `idle_timeout` is given up.
+pub async fn collect_llama_stream<S>(
+    mut stream: S,
+    idle_timeout: Duration,
+    on_delta: &mut OnDelta<'_>,
+) -> anyhow::Result<LlamaResponseMessage>
+where
+    S: Stream<Item = anyhow::Result<StreamChunk>> + Unpin,
+{
+    let mut text = String::new();
+    let mut role = Role::Assistant;
+    // id, name and arguments of each native tool call, by index
+    let mut tool_calls: BTreeMap<u32, (Option<String>, String, String)> = BTreeMap::new();
+    let mut usage = None;
+    loop {
+        let chunk = match tokio::time::timeout(idle_timeout, stream.next()).await {
+            Ok(Some(chunk)) => chunk,
+            Ok(None) => break,
+            Err(_) => {
+                return Err(anyhow::anyhow!(
+                    "Stream from OpenAI stalled for {:?}",
+                    idle_timeout
+                ))
+            }
+        };
+        let chunk = chunk.map_err(|e| anyhow::anyhow!("Stream from OpenAI broke off: {:?}", e))?;
+        if let Some(error) = chunk.error {
+            return Err(anyhow::anyhow!("Stream from OpenAI reported an error: {}", error));
+        }
+        if chunk.usage.is_some() {
+            usage = chunk.usage;
+        }
+        let Some(choice) = chunk.choices.into_iter().find(|choice| choice.index == 0) else {
+            continue;
+        };
+        if let Some(delta_role) = choice.delta.role {
+            role = delta_role;
+        }
+        if let Some(delta) = choice.delta.content {
+            on_delta(&delta);
+            text.push_str(&delta);
+        }
+        for piece in choice.delta.tool_calls.into_iter().flatten() {
+            let (id, name, arguments) = tool_calls.entry(piece.index).or_default();
+            if piece.id.is_some() {
+                *id = piece.id;
+            }
+            if let Some(function) = piece.function {
+                name.push_str(&function.name.unwrap_or_default());
+                arguments.push_str(&function.arguments.unwrap_or_default());
+            }
+        }
+    }
+
+    let usage = usage.unwrap_or(CompletionUsage {
+        prompt_tokens: 0,
+        completion_tokens: 0,
+        total_tokens: 0,
+    });
+    let mut tool_calls = tool_calls.into_values();
+    match (tool_calls.next(), tool_calls.len()) {
+        (None, _) => Ok(llama_response_from_text(text, role, usage)),
+        (Some((id, name, arguments)), 0) => Ok(LlamaResponseMessage {
+            content: Content::ToolCall(ToolCall {
+                name,
+                arguments: parse_tool_arguments(&arguments),
+                id,
+            }),
+            role,
+            usage,
+        }),
+        (Some(_), more) => Err(anyhow::anyhow!(
+            "The model made {} tool calls at once, only one is supported",
+            more + 1
+        )),
     }
 }
 
@@ -262,3 +766,340 @@ pub fn parse_issue_summary_from_json(input: &str) -> anyhow::Result<Vec<(String,
 
     Ok(summaries)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn unreachable_endpoint_is_an_error() {
+        let config = LlmClientConfig::new("http://127.0.0.1:9/v1/", "test-model", "key")
+            .with_header("x-team", "research")
+            .unwrap()
+            .with_query("api-version", "1")
+            .with_request_policy(RequestPolicy {
+                max_retries: 0,
+                ..RequestPolicy::default()
+            });
+        assert_eq!(config.provider.api_base, "http://127.0.0.1:9/v1");
+        assert!(config.clone().with_header("bad header", "x").is_err());
+
+        let messages = vec![Message::new(
+            Some(Content::Text("hi".to_string())),
+            None,
+            Some(Role::User),
+        )];
+        assert!(chat_inner_async_llama_with(&config, messages, 10)
+            .await
+            .is_err());
+    }
+
+    fn chunk(value: Value) -> anyhow::Result<StreamChunk> {
+        Ok(serde_json::from_value(value).unwrap())
+    }
+
+    fn text_chunk(role: Option<&str>, content: &str) -> anyhow::Result<StreamChunk> {
+        let mut delta = json!({ "content": content });
+        if let Some(role) = role {
+            delta["role"] = role.into();
+        }
+        chunk(json!({
+            "id": "chunk",
+            "choices": [{ "index": 0, "delta": delta, "finish_reason": null }],
+            "object": "chat.completion.chunk",
+        }))
+    }
+
+    #[tokio::test]
+    async fn streamed_deltas_are_joined_and_tool_calls_found_at_the_end() {
+        let chunks = vec![
+            text_chunk(Some("assistant"), "<tool_call>{\"name\": \"get_weather\", "),
+            text_chunk(None, "\"arguments\": {\"city\": \"Paris\"}}"),
+            text_chunk(None, "</tool_call>"),
+            chunk(json!({
+                "choices": [],
+                "usage": { "prompt_tokens": 12, "completion_tokens": 9, "total_tokens": 21 }
+            })),
+        ];
+        let mut seen = Vec::new();
+        let reply = collect_llama_stream(
+            futures::stream::iter(chunks),
+            Duration::from_secs(5),
+            &mut |delta| seen.push(delta.to_string()),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(seen.len(), 3);
+        match reply.content {
+            Content::ToolCall(tool_call) => assert_eq!(tool_call.name, "get_weather"),
+            Content::Text(text) => panic!("expected a tool call, got {}", text),
+        }
+        assert_eq!(reply.usage.prompt_tokens, 12);
+        assert_eq!(reply.usage.completion_tokens, 9);
+        assert_eq!(reply.usage.total_tokens, 21);
+    }
+
+    #[tokio::test]
+    async fn native_tool_call_pieces_are_put_together() {
+        let piece = |tool_call: Value| {
+            chunk(json!({ "choices": [{ "index": 0, "delta": { "tool_calls": [tool_call] } }] }))
+        };
+        let chunks = vec![
+            chunk(json!({ "choices": [{ "index": 0, "delta": { "role": "assistant" } }] })),
+            piece(json!({
+                "index": 0,
+                "id": "call_abc",
+                "type": "function",
+                "function": { "name": "get_weather", "arguments": "" }
+            })),
+            piece(json!({ "index": 0, "function": { "arguments": "{\"city\": " } })),
+            piece(json!({ "index": 0, "function": { "arguments": "\"Rome\"}" } })),
+        ];
+        let reply = collect_llama_stream(
+            futures::stream::iter(chunks),
+            Duration::from_secs(5),
+            &mut |_| {},
+        )
+        .await
+        .unwrap();
+
+        match reply.content {
+            Content::ToolCall(tool_call) => {
+                assert_eq!(tool_call.name, "get_weather");
+                assert_eq!(tool_call.id.as_deref(), Some("call_abc"));
+                assert_eq!(tool_call.arguments.unwrap()["city"], "Rome");
+            }
+            Content::Text(text) => panic!("expected a tool call, got {}", text),
+        }
+        // no usage chunk, nothing made up
+        assert_eq!(reply.usage.total_tokens, 0);
+    }
+
+    #[test]
+    fn sse_events_survive_any_split_of_the_bytes() {
+        let body = "data: {\"a\": \"é\"}\r\n\r\n: keep-alive\n\ndata: [DONE]\n\n".as_bytes();
+        for split in 0..body.len() {
+            let mut reader = SseReader::default();
+            let mut events = reader.push(&body[..split]);
+            events.extend(reader.push(&body[split..]));
+            events.extend(reader.finish());
+            assert_eq!(events, vec!["{\"a\": \"é\"}", "[DONE]"]);
+        }
+        let mut reader = SseReader::default();
+        assert!(reader.push(b"data: unterminated").is_empty());
+        assert_eq!(reader.finish(), vec!["unterminated"]);
+    }
+
+    #[tokio::test]
+    async fn a_stream_asks_for_usage_and_reads_it_back() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buffer = [0u8; 4096];
+            // the body is complete once it closes its JSON object
+            while !request.ends_with(b"}") {
+                let read = socket.read(&mut buffer).await.unwrap();
+                request.extend_from_slice(&buffer[..read]);
+            }
+            let events = [
+                json!({
+                    "choices": [{ "index": 0, "delta": { "role": "assistant", "content": "Hel" } }]
+                }),
+                json!({ "choices": [{ "index": 0, "delta": { "content": "lo" } }] }),
+                json!({
+                    "choices": [],
+                    "usage": { "prompt_tokens": 3, "completion_tokens": 2, "total_tokens": 5 }
+                }),
+            ];
+            let mut response = String::from(
+                "HTTP/1.1 200 OK\r\ncontent-type: text/event-stream\r\nconnection: close\r\n\r\n",
+            );
+            for event in events {
+                response.push_str(&format!("data: {}\n\n", event));
+            }
+            response.push_str("data: [DONE]\n\n");
+            socket.write_all(response.as_bytes()).await.unwrap();
+            String::from_utf8(request).unwrap()
+        });
+
+        let config = LlmClientConfig::new(&format!("http://{}/v1", address), "test-model", "key");
+        let messages = vec![Message::new(
+            Some(Content::Text("hi".to_string())),
+            None,
+            Some(Role::User),
+        )];
+        let mut seen = String::new();
+        let reply =
+            chat_inner_async_llama_stream(&config, messages, 10, &mut |delta| seen.push_str(delta))
+                .await
+                .unwrap();
+
+        assert_eq!(seen, "Hello");
+        assert_eq!(reply.content, Content::Text("Hello".to_string()));
+        assert_eq!(reply.usage.total_tokens, 5);
+        let request = server.await.unwrap();
+        let body: Value = serde_json::from_str(&request[request.find("\r\n\r\n").unwrap() + 4..])
+            .unwrap();
+        assert_eq!(body["stream"], true);
+        assert_eq!(body["stream_options"]["include_usage"], true);
+    }
+
+    #[test]
+    fn tool_calls_map_to_and_from_the_native_fields() {
+        let mut arguments = HashMap::new();
+        arguments.insert("city".to_string(), "Paris".to_string());
+        let history = vec![
+            Message::new(
+                Some(Content::Text("Weather in Paris?".to_string())),
+                None,
+                Some(Role::User),
+            ),
+            Message::new(
+                Some(Content::ToolCall(ToolCall {
+                    name: "get_weather".to_string(),
+                    arguments: Some(arguments),
+                    id: None,
+                })),
+                None,
+                Some(Role::Assistant),
+            ),
+            Message::new(
+                Some(Content::Text("sunny".to_string())),
+                Some("get_weather".to_string()),
+                Some(Role::Tool),
+            ),
+        ];
+        let request = serde_json::to_value(to_request_messages(history, true).unwrap()).unwrap();
+        assert_eq!(request[1]["tool_calls"][0]["id"], "call_1");
+        assert_eq!(
+            request[1]["tool_calls"][0]["function"]["arguments"],
+            r#"{"city":"Paris"}"#
+        );
+        assert_eq!(request[2]["role"], "tool");
+        assert_eq!(request[2]["tool_call_id"], "call_1");
+
+        let tools = to_native_tools(&[json!({
+            "name": "get_weather",
+            "parameters": { "type": "object", "properties": {} },
+        })])
+        .unwrap();
+        assert_eq!(tools[0].function.name, "get_weather");
+
+        let response: CreateChatCompletionResponse = serde_json::from_value(json!({
+            "id": "chat",
+            "object": "chat.completion",
+            "created": 0,
+            "model": "test-model",
+            "choices": [{
+                "index": 0,
+                "finish_reason": "tool_calls",
+                "message": {
+                    "role": "assistant",
+                    "content": null,
+                    "tool_calls": [{
+                        "id": "call_abc",
+                        "type": "function",
+                        "function": {
+                            "name": "get_weather",
+                            "arguments": "{\"city\": \"Rome\"}"
+                        }
+                    }]
+                }
+            }],
+            "usage": { "prompt_tokens": 5, "completion_tokens": 7, "total_tokens": 12 }
+        }))
+        .unwrap();
+        match output_llama_response(response).unwrap().content {
+            Content::ToolCall(tool_call) => {
+                assert_eq!(tool_call.id.as_deref(), Some("call_abc"));
+                assert_eq!(tool_call.arguments.unwrap()["city"], "Rome");
+            }
+            Content::Text(text) => panic!("expected a tool call, got {}", text),
+        }
+    }
+
+    fn completion(choices: Value, usage: Option<Value>) -> CreateChatCompletionResponse {
+        let mut response = json!({
+            "id": "chat",
+            "object": "chat.completion",
+            "created": 0,
+            "model": "test-model",
+            "choices": choices,
+        });
+        if let Some(usage) = usage {
+            response["usage"] = usage;
+        }
+        serde_json::from_value(response).unwrap()
+    }
+
+    #[test]
+    fn replies_without_usage_or_choices_or_with_several_tool_calls() {
+        let text = json!([{
+            "index": 0,
+            "finish_reason": "stop",
+            "message": { "role": "assistant", "content": "Hello" }
+        }]);
+        let reply = output_llama_response(completion(text, None)).unwrap();
+        assert_eq!(reply.content, Content::Text("Hello".to_string()));
+        assert_eq!(reply.usage.total_tokens, 0);
+
+        assert!(output_llama_response(completion(json!([]), None)).is_err());
+
+        let call = |id: &str| {
+            json!({
+                "id": id,
+                "type": "function",
+                "function": { "name": "get_weather", "arguments": "{}" }
+            })
+        };
+        let calls = json!([{
+            "index": 0,
+            "finish_reason": "tool_calls",
+            "message": { "role": "assistant", "tool_calls": [call("call_a"), call("call_b")] }
+        }]);
+        let error = output_llama_response(completion(calls, None)).unwrap_err();
+        assert!(error.to_string().contains("2 tool calls"));
+    }
+
+    #[test]
+    fn text_tool_calls_keep_arguments_of_any_type() {
+        let text = concat!(
+            r#"<tool_call>{"name": "book", "arguments": "#,
+            r#"{"seats": 2, "window": true, "when": {"day": "friday"}, "note": "aisle"}}"#,
+            "</tool_call>"
+        );
+        let usage = CompletionUsage {
+            prompt_tokens: 0,
+            completion_tokens: 0,
+            total_tokens: 0,
+        };
+        let reply = llama_response_from_text(text.to_string(), Role::Assistant, usage);
+        match reply.content {
+            Content::ToolCall(tool_call) => {
+                assert_eq!(tool_call.name, "book");
+                let arguments = tool_call.arguments.unwrap();
+                assert_eq!(arguments["seats"], "2");
+                assert_eq!(arguments["window"], "true");
+                assert_eq!(arguments["when"], r#"{"day":"friday"}"#);
+                assert_eq!(arguments["note"], "aisle");
+            }
+            Content::Text(text) => panic!("expected a tool call, got {}", text),
+        }
+    }
+
+    #[test]
+    fn a_message_without_a_role_is_an_error() {
+        let history = vec![Message {
+            content: Some(Content::Text("hello".to_string())),
+            name: None,
+            role: None,
+        }];
+        assert!(to_request_messages(history.clone(), false).is_err());
+        assert!(to_request_messages(history, true).is_err());
+    }
+}
@@ -4,7 +4,6 @@ use autogen_rust::llama_structs::*;
 use autogen_rust::message_store::*;
 // use autogen_rust::tool_call_actuators::*;
 use anyhow::Result;
-use rusqlite::Connection;
 use tokio;
 
 #[tokio::main]
@@ -57,20 +56,8 @@ async fn main() -> Result<()> {
 
     // let code = coding_agent.start_coding(&message).await?;
     // println!("{:?}", code);
-    let conn = Connection::open_in_memory()?;
-
-    conn.execute(
-        "CREATE TABLE IF NOT EXISTS GroupChat (
-            id INTEGER PRIMARY KEY,
-            agent_name TEXT NOT NULL,
-            message_content TEXT,
-            message_role TEXT,
-            message_context TEXT,
-            tokens_count INTEGER,
-            next_speaker TEXT
-        )",
-        [],
-    )?;
+    let store = SqliteMessageStore::open_in_memory()?;
+    let conversation_id = store.create_conversation(None, Some("demo"))?;
 
     let messages = vec![
         Message {
@@ -87,6 +74,7 @@ async fn main() -> Result<()> {
             content: Some(Content::ToolCall(ToolCall {
                 name: "search".to_string(),
                 arguments: Some(std::collections::HashMap::new()),
+                id: None,
             })),
             name: Some("Agent1".to_string()),
             role: Some(Role::Tool),
@@ -94,9 +82,14 @@ async fn main() -> Result<()> {
     ];
 
     for message in messages {
-        save_message(&conn, "Agent1".to_string(), message.clone(), "Agent2".to_string())?;
+        store.save_message(
+            &conversation_id,
+            "Agent1".to_string(),
+            message.clone(),
+            "Agent2".to_string(),
+        )?;
     }
-    let messages = retrieve_messages(&conn, "Agent1".to_string())?;
+    let messages = store.retrieve_messages(&conversation_id, "Agent1".to_string())?;
     for message in messages {
         println!("{:?}", message);
     }
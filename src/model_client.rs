@@ -0,0 +1,385 @@
+use crate::conversable_agent::Message;
+use crate::history_compression::compress_history;
+use crate::llama_structs::{llama_response_from_text, Content, LlamaResponseMessage};
+use crate::llm_llama_local::{
+    chat_inner_async_llama_stream, chat_inner_async_llama_tools,
+    chat_inner_async_llama_uncompressed, default_llm_config, LlmClientConfig, OnDelta,
+};
+use crate::redaction::redact_prompt;
+use crate::request_policy::{is_retryable_status, send_with_policy, AttemptError, RequestPolicy};
+use async_openai::config::Config;
+use async_openai::types::{CompletionUsage, Role};
+use async_trait::async_trait;
+use reqwest::header::CONTENT_TYPE;
+use serde_json::{json, Value};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+#[async_trait]
+pub trait ModelClient: Send + Sync {
+    // Part of the response cache key; empty when the backend cannot tell.
+    fn model(&self) -> String {
+        String::new()
+    }
+
+    // Also part of the key, so the same model served from two places is cached apart.
+    fn endpoint(&self) -> String {
+        String::new()
+    }
+
+    // Whether tools go out as API fields rather than in the prompt; part of the key as well.
+    fn native_tools(&self) -> bool {
+        false
+    }
+
+    // The client underneath a caching layer, for callers that want a fresh answer.
+    fn without_cache(&self) -> Option<Arc<dyn ModelClient>> {
+        None
+    }
+
+    // Sends the messages as they are.
+    async fn complete(
+        &self,
+        messages: Vec<Message>,
+        max_token: u16,
+    ) -> anyhow::Result<LlamaResponseMessage>;
+
+    // What agents call: the prompt is redacted and, when the history is too long for the model,
+    // compressed first (see HISTORY_COMPRESSION).
+    async fn chat(
+        &self,
+        messages: Vec<Message>,
+        max_token: u16,
+    ) -> anyhow::Result<LlamaResponseMessage> {
+        let messages = compress_history(self, redact_prompt(messages), max_token).await;
+        self.complete(messages, max_token).await
+    }
+
+    // Backends without function calling of their own ignore the tools; the prompt has to
+    // describe them and ask for a <tool_call> block instead.
+    async fn complete_with_tools(
+        &self,
+        messages: Vec<Message>,
+        _tools: &[Value],
+        max_token: u16,
+    ) -> anyhow::Result<LlamaResponseMessage> {
+        self.complete(messages, max_token).await
+    }
+
+    async fn chat_with_tools(
+        &self,
+        messages: Vec<Message>,
+        tools: &[Value],
+        max_token: u16,
+    ) -> anyhow::Result<LlamaResponseMessage> {
+        let messages = compress_history(self, redact_prompt(messages), max_token).await;
+        self.complete_with_tools(messages, tools, max_token).await
+    }
+
+    // Backends without streaming hand the whole answer over as a single delta.
+    async fn complete_stream(
+        &self,
+        messages: Vec<Message>,
+        max_token: u16,
+        on_delta: &mut OnDelta<'_>,
+    ) -> anyhow::Result<LlamaResponseMessage> {
+        let reply = self.complete(messages, max_token).await?;
+        if let Content::Text(text) = &reply.content {
+            on_delta(text);
+        }
+        Ok(reply)
+    }
+
+    async fn chat_stream(
+        &self,
+        messages: Vec<Message>,
+        max_token: u16,
+        on_delta: &mut OnDelta<'_>,
+    ) -> anyhow::Result<LlamaResponseMessage> {
+        let messages = compress_history(self, redact_prompt(messages), max_token).await;
+        self.complete_stream(messages, max_token, on_delta).await
+    }
+}
+
+// Any server speaking the OpenAI /v1/chat/completions protocol: llama.cpp, vLLM, a hosted API.
+#[derive(Clone, Debug, Default)]
+pub struct OpenAiCompatibleClient {
+    // resolved on every call from the process-wide default when None
+    pub config: Option<LlmClientConfig>,
+}
+
+impl OpenAiCompatibleClient {
+    pub fn new(config: LlmClientConfig) -> Self {
+        OpenAiCompatibleClient {
+            config: Some(config),
+        }
+    }
+}
+
+#[async_trait]
+impl ModelClient for OpenAiCompatibleClient {
+    fn model(&self) -> String {
+        match &self.config {
+            Some(config) => config.model.clone(),
+            None => default_llm_config()
+                .map(|config| config.model)
+                .unwrap_or_default(),
+        }
+    }
+
+    fn endpoint(&self) -> String {
+        match &self.config {
+            Some(config) => config.provider.api_base().to_string(),
+            None => default_llm_config()
+                .map(|config| config.provider.api_base().to_string())
+                .unwrap_or_default(),
+        }
+    }
+
+    fn native_tools(&self) -> bool {
+        match &self.config {
+            Some(config) => config.native_tools,
+            None => default_llm_config()
+                .map(|config| config.native_tools)
+                .unwrap_or_default(),
+        }
+    }
+
+    async fn complete(
+        &self,
+        messages: Vec<Message>,
+        max_token: u16,
+    ) -> anyhow::Result<LlamaResponseMessage> {
+        let config = match &self.config {
+            Some(config) => config.clone(),
+            None => default_llm_config()?,
+        };
+        chat_inner_async_llama_uncompressed(&config, messages, max_token).await
+    }
+
+    async fn complete_with_tools(
+        &self,
+        messages: Vec<Message>,
+        tools: &[Value],
+        max_token: u16,
+    ) -> anyhow::Result<LlamaResponseMessage> {
+        let config = match &self.config {
+            Some(config) => config.clone(),
+            None => default_llm_config()?,
+        };
+        chat_inner_async_llama_tools(&config, messages, tools, max_token).await
+    }
+
+    async fn complete_stream(
+        &self,
+        messages: Vec<Message>,
+        max_token: u16,
+        on_delta: &mut OnDelta<'_>,
+    ) -> anyhow::Result<LlamaResponseMessage> {
+        let config = match &self.config {
+            Some(config) => config.clone(),
+            None => default_llm_config()?,
+        };
+        chat_inner_async_llama_stream(&config, messages, max_token, on_delta).await
+    }
+}
+
+pub fn default_model_client() -> Arc<dyn ModelClient> {
+    Arc::new(OpenAiCompatibleClient::default())
+}
+
+// Ollama's native /api/chat endpoint, without streaming.
+pub struct OllamaClient {
+    pub base_url: String,
+    pub model: String,
+    pub request_policy: RequestPolicy,
+    http: reqwest::Client,
+}
+
+impl OllamaClient {
+    pub fn new(base_url: &str, model: &str) -> Self {
+        OllamaClient {
+            base_url: base_url.trim_end_matches('/').to_string(),
+            model: model.to_string(),
+            request_policy: RequestPolicy::default(),
+            http: reqwest::Client::new(),
+        }
+    }
+}
+
+fn ollama_role(role: Option<Role>) -> &'static str {
+    match role {
+        Some(Role::System) => "system",
+        Some(Role::User) => "user",
+        Some(Role::Tool) | Some(Role::Function) => "tool",
+        _ => "assistant",
+    }
+}
+
+#[async_trait]
+impl ModelClient for OllamaClient {
+    fn model(&self) -> String {
+        self.model.clone()
+    }
+
+    fn endpoint(&self) -> String {
+        self.base_url.clone()
+    }
+
+    async fn complete(
+        &self,
+        messages: Vec<Message>,
+        max_token: u16,
+    ) -> anyhow::Result<LlamaResponseMessage> {
+        let messages: Vec<Value> = messages
+            .iter()
+            .map(|message| {
+                json!({
+                    "role": ollama_role(message.role),
+                    "content": message.content_to_string().unwrap_or_default(),
+                })
+            })
+            .collect();
+        let body = json!({
+            "model": self.model,
+            "messages": messages,
+            "stream": false,
+            "options": { "num_predict": max_token },
+        });
+
+        let url = format!("{}/api/chat", self.base_url);
+        let (url, body) = (&url, &body.to_string());
+        let text = send_with_policy(&self.request_policy, &self.base_url, || async move {
+            let response = self
+                .http
+                .post(url)
+                .header(CONTENT_TYPE, "application/json")
+                .body(body.clone())
+                .send()
+                .await?;
+            let status = response.status();
+            let text = response.text().await?;
+            if status.is_success() {
+                return Ok(text);
+            }
+            let error = anyhow::anyhow!("Ollama returned {}: {}", status, text);
+            Err(match is_retryable_status(status.as_u16()) {
+                true => AttemptError::retryable(error),
+                false => AttemptError::permanent(error),
+            })
+        })
+        .await?;
+
+        let reply: Value = serde_json::from_str(&text)?;
+        let content = reply["message"]["content"]
+            .as_str()
+            .ok_or_else(|| anyhow::anyhow!("No message content in Ollama reply: {}", text))?;
+        let prompt_tokens = reply["prompt_eval_count"].as_u64().unwrap_or(0) as u32;
+        let completion_tokens = reply["eval_count"].as_u64().unwrap_or(0) as u32;
+        Ok(llama_response_from_text(
+            content.to_string(),
+            Role::Assistant,
+            CompletionUsage {
+                prompt_tokens,
+                completion_tokens,
+                total_tokens: prompt_tokens + completion_tokens,
+            },
+        ))
+    }
+}
+
+// Returns canned responses in order and keeps every request, for tests without a server.
+// Runs out with an error once the script is used up.
+#[derive(Default)]
+pub struct ScriptedModelClient {
+    responses: Mutex<VecDeque<LlamaResponseMessage>>,
+    requests: Mutex<Vec<Vec<Message>>>,
+}
+
+impl ScriptedModelClient {
+    pub fn new(responses: Vec<LlamaResponseMessage>) -> Self {
+        ScriptedModelClient {
+            responses: Mutex::new(responses.into()),
+            requests: Mutex::new(Vec::new()),
+        }
+    }
+
+    // Texts are read like a server reply, so "<tool_call>...</tool_call>" scripts a tool call.
+    pub fn from_texts(texts: &[&str]) -> Self {
+        Self::new(
+            texts
+                .iter()
+                .map(|text| {
+                    llama_response_from_text(
+                        text.to_string(),
+                        Role::Assistant,
+                        CompletionUsage {
+                            prompt_tokens: 0,
+                            completion_tokens: 0,
+                            total_tokens: 0,
+                        },
+                    )
+                })
+                .collect(),
+        )
+    }
+
+    pub fn requests(&self) -> Vec<Vec<Message>> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.responses.lock().unwrap().len()
+    }
+}
+
+#[async_trait]
+impl ModelClient for ScriptedModelClient {
+    fn model(&self) -> String {
+        "scripted".to_string()
+    }
+
+    async fn complete(
+        &self,
+        messages: Vec<Message>,
+        _max_token: u16,
+    ) -> anyhow::Result<LlamaResponseMessage> {
+        self.requests.lock().unwrap().push(messages);
+        self.responses
+            .lock()
+            .unwrap()
+            .pop_front()
+            .ok_or_else(|| anyhow::anyhow!("Scripted model client has no responses left"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conversable_agent::ConversableAgent;
+
+    #[tokio::test]
+    async fn agent_replies_from_the_script_and_stops_when_it_runs_out() {
+        let client = Arc::new(ScriptedModelClient::from_texts(&["Hello there."]));
+        let mut agent = ConversableAgent::new("assistant");
+        agent.set_model_client(client.clone());
+
+        let question = Message::new(
+            Some(Content::Text("Say hello".to_string())),
+            None,
+            Some(Role::User),
+        );
+        let reply = agent
+            .a_generate_reply(vec![question.clone()], None)
+            .await
+            .unwrap();
+        assert_eq!(
+            reply.content,
+            Some(Content::Text("Hello there.".to_string()))
+        );
+        assert_eq!(client.requests(), vec![vec![question.clone()]]);
+        assert_eq!(client.remaining(), 0);
+
+        assert!(agent.a_generate_reply(vec![question], None).await.is_none());
+    }
+}
@@ -0,0 +1,264 @@
+use lazy_static::lazy_static;
+use rand::Rng;
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// A steady rate with room for short bursts.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+    pub requests_per_second: f64,
+    pub burst: u32,
+}
+
+// How one request to a model server is sent: how long an attempt may take, how often it is
+// repeated and how far apart, and how fast requests to the endpoint may go out at all.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RequestPolicy {
+    pub timeout: Duration,
+    pub max_retries: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    pub rate_limit: Option<RateLimit>,
+}
+
+impl Default for RequestPolicy {
+    fn default() -> Self {
+        RequestPolicy {
+            timeout: Duration::from_secs(120),
+            max_retries: 3,
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(10),
+            rate_limit: None,
+        }
+    }
+}
+
+impl RequestPolicy {
+    // Exponential growth with full jitter, so agents that failed together do not come back
+    // together.
+    pub fn backoff(&self, retry: u32) -> Duration {
+        let ceiling = self
+            .initial_backoff
+            .saturating_mul(2u32.saturating_pow(retry))
+            .min(self.max_backoff);
+        ceiling.mul_f64(rand::thread_rng().gen_range(0.0..=1.0))
+    }
+}
+
+pub struct TokenBucket {
+    pub rate_limit: RateLimit,
+    // available tokens and when they were last topped up
+    state: Mutex<(f64, Instant)>,
+}
+
+impl TokenBucket {
+    pub fn new(rate_limit: RateLimit) -> Self {
+        TokenBucket {
+            rate_limit,
+            state: Mutex::new((rate_limit.burst.max(1) as f64, Instant::now())),
+        }
+    }
+
+    // How long the caller has to wait before a token is free, taking it if there is one.
+    fn try_take(&self) -> Option<Duration> {
+        let mut state = self.state.lock().unwrap();
+        let (tokens, refilled) = &mut *state;
+        let now = Instant::now();
+        let rate = self.rate_limit.requests_per_second.max(f64::MIN_POSITIVE);
+        *tokens = (*tokens + now.duration_since(*refilled).as_secs_f64() * rate)
+            .min(self.rate_limit.burst.max(1) as f64);
+        *refilled = now;
+        if *tokens >= 1.0 {
+            *tokens -= 1.0;
+            None
+        } else {
+            Some(Duration::from_secs_f64((1.0 - *tokens) / rate))
+        }
+    }
+
+    pub async fn acquire(&self) {
+        while let Some(wait) = self.try_take() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+lazy_static! {
+    // One bucket per endpoint and limit, shared by every agent and client that asks for it.
+    // The limit's rate is keyed by its bits, since f64 is not Hash.
+    static ref RATE_LIMITERS: Mutex<HashMap<(String, u64, u32), Arc<TokenBucket>>> =
+        Mutex::new(HashMap::new());
+}
+
+// Clients of the same endpoint with the same limit share its bucket. Clients with different
+// limits each keep their own, so none of them loses its pacing to another's configuration.
+pub fn rate_limiter_for(endpoint: &str, rate_limit: RateLimit) -> Arc<TokenBucket> {
+    let key = (
+        endpoint.to_string(),
+        rate_limit.requests_per_second.to_bits(),
+        rate_limit.burst,
+    );
+    RATE_LIMITERS
+        .lock()
+        .unwrap()
+        .entry(key)
+        .or_insert_with(|| Arc::new(TokenBucket::new(rate_limit)))
+        .clone()
+}
+
+pub fn is_retryable_status(status: u16) -> bool {
+    status == 408 || status == 429 || (500..600).contains(&status)
+}
+
+#[derive(Debug)]
+pub struct AttemptError {
+    pub error: anyhow::Error,
+    pub retryable: bool,
+}
+
+impl AttemptError {
+    pub fn retryable(error: anyhow::Error) -> Self {
+        AttemptError {
+            error,
+            retryable: true,
+        }
+    }
+
+    pub fn permanent(error: anyhow::Error) -> Self {
+        AttemptError {
+            error,
+            retryable: false,
+        }
+    }
+}
+
+// Connection problems and timeouts are worth another try; anything about the request is not.
+impl From<reqwest::Error> for AttemptError {
+    fn from(error: reqwest::Error) -> Self {
+        let retryable = error.is_timeout()
+            || error.is_connect()
+            || error.is_request()
+            || error.is_body()
+            || error
+                .status()
+                .is_some_and(|status| is_retryable_status(status.as_u16()));
+        AttemptError {
+            error: error.into(),
+            retryable,
+        }
+    }
+}
+
+// Runs `attempt` under the policy: each try waits for the endpoint's rate limiter and is cut
+// off after the timeout, and retryable failures are repeated after a backoff.
+pub async fn send_with_policy<T, F, Fut>(
+    policy: &RequestPolicy,
+    endpoint: &str,
+    mut attempt: F,
+) -> anyhow::Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, AttemptError>>,
+{
+    let limiter = policy
+        .rate_limit
+        .map(|rate_limit| rate_limiter_for(endpoint, rate_limit));
+    let mut retry = 0;
+    loop {
+        if let Some(limiter) = &limiter {
+            limiter.acquire().await;
+        }
+        let failure = match tokio::time::timeout(policy.timeout, attempt()).await {
+            Ok(Ok(value)) => return Ok(value),
+            Ok(Err(failure)) => failure,
+            Err(_) => AttemptError::retryable(anyhow::anyhow!(
+                "No answer from {} within {:?}",
+                endpoint,
+                policy.timeout
+            )),
+        };
+        if !failure.retryable || retry >= policy.max_retries {
+            return Err(failure.error);
+        }
+        let wait = policy.backoff(retry);
+        println!(
+            "Request to {} failed, retrying in {:?}: {:?}",
+            endpoint, wait, failure.error
+        );
+        tokio::time::sleep(wait).await;
+        retry += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    #[tokio::test]
+    async fn retryable_failures_are_repeated_and_permanent_ones_are_not() {
+        let policy = RequestPolicy {
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_millis(5),
+            ..RequestPolicy::default()
+        };
+        let attempts = AtomicU32::new(0);
+        let answer = send_with_policy(&policy, "test-retry", || async {
+            match attempts.fetch_add(1, Ordering::SeqCst) {
+                0 | 1 => Err(AttemptError::retryable(anyhow::anyhow!("503"))),
+                _ => Ok("ok"),
+            }
+        })
+        .await;
+        assert_eq!(answer.unwrap(), "ok");
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+
+        attempts.store(0, Ordering::SeqCst);
+        let answer: anyhow::Result<()> = send_with_policy(&policy, "test-retry", || async {
+            attempts.fetch_add(1, Ordering::SeqCst);
+            Err(AttemptError::permanent(anyhow::anyhow!("400")))
+        })
+        .await;
+        assert!(answer.is_err());
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn the_bucket_lets_a_burst_through_and_then_paces_requests() {
+        let limiter = rate_limiter_for(
+            "test-bucket",
+            RateLimit {
+                requests_per_second: 20.0,
+                burst: 2,
+            },
+        );
+        let started = Instant::now();
+        for _ in 0..4 {
+            limiter.acquire().await;
+        }
+        // two from the burst, two more at 50ms each
+        assert!(started.elapsed() >= Duration::from_millis(90));
+    }
+
+    #[test]
+    fn clients_with_different_limits_on_one_endpoint_keep_their_own_buckets() {
+        let slow = RateLimit {
+            requests_per_second: 1.0,
+            burst: 1,
+        };
+        let fast = RateLimit {
+            requests_per_second: 100.0,
+            burst: 10,
+        };
+        let first = rate_limiter_for("test-shared-endpoint", slow);
+        let second = rate_limiter_for("test-shared-endpoint", fast);
+        assert!(!Arc::ptr_eq(&first, &second));
+        assert_eq!(first.rate_limit, slow);
+        assert_eq!(second.rate_limit, fast);
+        // asking again with a known limit gives back the same bucket
+        let again = rate_limiter_for("test-shared-endpoint", slow);
+        assert!(Arc::ptr_eq(&first, &again));
+    }
+}
@@ -0,0 +1,382 @@
+use crate::conversable_agent::Message;
+use crate::llama_structs::{Content, LlamaResponseMessage};
+use crate::llm_llama_local::OnDelta;
+use crate::message_store::now_timestamp;
+use crate::model_client::ModelClient;
+use async_trait::async_trait;
+use rusqlite::{params, Connection, OptionalExtension};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+pub trait ResponseCache: Send + Sync {
+    fn get(&self, key: &str) -> anyhow::Result<Option<LlamaResponseMessage>>;
+
+    fn put(&self, key: &str, response: &LlamaResponseMessage) -> anyhow::Result<()>;
+}
+
+// The model that answers and how it is reached: its endpoint, and whether tools are sent as
+// API fields or described in the prompt.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CacheTarget {
+    pub model: String,
+    pub endpoint: String,
+    pub native_tools: bool,
+}
+
+impl CacheTarget {
+    pub fn of(client: &dyn ModelClient) -> Self {
+        CacheTarget {
+            model: client.model(),
+            endpoint: client.endpoint(),
+            native_tools: client.native_tools(),
+        }
+    }
+}
+
+// Hex SHA-256 of everything that decides the answer. Redaction and history compression run
+// before the client is called, so the key covers the messages as they are actually sent.
+pub fn cache_key(
+    target: &CacheTarget,
+    messages: &[Message],
+    tools: &[Value],
+    max_token: u16,
+    seed: Option<&str>,
+) -> String {
+    let request = json!({
+        "model": target.model,
+        "endpoint": target.endpoint,
+        "native_tools": target.native_tools,
+        "messages": messages,
+        "tools": tools,
+        "max_token": max_token,
+        "seed": seed,
+    });
+    Sha256::digest(request.to_string().as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+pub struct SqliteResponseCache {
+    conn: Mutex<Connection>,
+}
+
+impl SqliteResponseCache {
+    pub fn open<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
+        Self::from_connection(Connection::open(path)?)
+    }
+
+    pub fn open_in_memory() -> anyhow::Result<Self> {
+        Self::from_connection(Connection::open_in_memory()?)
+    }
+
+    // The table can live next to the message tables in the same file.
+    pub fn from_connection(conn: Connection) -> anyhow::Result<Self> {
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS ResponseCache (
+                key TEXT PRIMARY KEY,
+                response TEXT NOT NULL,
+                created_at INTEGER NOT NULL
+            )",
+            [],
+        )?;
+        Ok(SqliteResponseCache {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    pub fn clear(&self) -> anyhow::Result<usize> {
+        Ok(self
+            .conn
+            .lock()
+            .unwrap()
+            .execute("DELETE FROM ResponseCache", [])?)
+    }
+}
+
+impl ResponseCache for SqliteResponseCache {
+    fn get(&self, key: &str) -> anyhow::Result<Option<LlamaResponseMessage>> {
+        let response: Option<String> = self
+            .conn
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT response FROM ResponseCache WHERE key = ?1",
+                params![key],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(match response {
+            Some(response) => Some(serde_json::from_str(&response)?),
+            None => None,
+        })
+    }
+
+    fn put(&self, key: &str, response: &LlamaResponseMessage) -> anyhow::Result<()> {
+        self.conn.lock().unwrap().execute(
+            "INSERT OR REPLACE INTO ResponseCache (key, response, created_at) VALUES (?1, ?2, ?3)",
+            params![key, serde_json::to_string(response)?, now_timestamp()],
+        )?;
+        Ok(())
+    }
+}
+
+// One pretty-printed JSON file per response, easy to inspect or check into a fixtures folder.
+pub struct DirectoryResponseCache {
+    pub dir: PathBuf,
+}
+
+impl DirectoryResponseCache {
+    pub fn new<P: AsRef<Path>>(dir: P) -> anyhow::Result<Self> {
+        std::fs::create_dir_all(&dir)?;
+        Ok(DirectoryResponseCache {
+            dir: dir.as_ref().to_path_buf(),
+        })
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", key))
+    }
+}
+
+impl ResponseCache for DirectoryResponseCache {
+    fn get(&self, key: &str) -> anyhow::Result<Option<LlamaResponseMessage>> {
+        match std::fs::read_to_string(self.path(key)) {
+            Ok(text) => Ok(Some(serde_json::from_str(&text)?)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    // Written to a temporary file first, so a reader never sees half a response.
+    fn put(&self, key: &str, response: &LlamaResponseMessage) -> anyhow::Result<()> {
+        let path = self.path(key);
+        // a name of its own, so concurrent writers of one key never share a half-written file
+        let partial = self
+            .dir
+            .join(format!("{}.{}.partial", key, uuid::Uuid::new_v4()));
+        std::fs::write(&partial, serde_json::to_string_pretty(response)?)?;
+        std::fs::rename(partial, path)?;
+        Ok(())
+    }
+}
+
+// Answers identical requests from the cache and sends the rest on to `inner`. A cache that
+// cannot be read or written is reported and otherwise ignored.
+pub struct CachedModelClient {
+    pub inner: Arc<dyn ModelClient>,
+    pub cache: Arc<dyn ResponseCache>,
+    // changes every key, e.g. to record a second set of answers next to the first
+    pub seed: Option<String>,
+}
+
+impl CachedModelClient {
+    pub fn new(inner: Arc<dyn ModelClient>, cache: Arc<dyn ResponseCache>) -> Self {
+        CachedModelClient {
+            inner,
+            cache,
+            seed: None,
+        }
+    }
+
+    pub fn with_seed(mut self, seed: &str) -> Self {
+        self.seed = Some(seed.to_string());
+        self
+    }
+
+    fn key(&self, messages: &[Message], tools: &[Value], max_token: u16) -> String {
+        cache_key(
+            &CacheTarget::of(self.inner.as_ref()),
+            messages,
+            tools,
+            max_token,
+            self.seed.as_deref(),
+        )
+    }
+
+    fn lookup(&self, key: &str) -> Option<LlamaResponseMessage> {
+        self.cache.get(key).unwrap_or_else(|e| {
+            println!("Error reading response cache: {:?}", e);
+            None
+        })
+    }
+
+    fn store(&self, key: &str, response: &LlamaResponseMessage) {
+        if let Err(e) = self.cache.put(key, response) {
+            println!("Error writing response cache: {:?}", e);
+        }
+    }
+}
+
+#[async_trait]
+impl ModelClient for CachedModelClient {
+    fn model(&self) -> String {
+        self.inner.model()
+    }
+
+    fn endpoint(&self) -> String {
+        self.inner.endpoint()
+    }
+
+    fn native_tools(&self) -> bool {
+        self.inner.native_tools()
+    }
+
+    fn without_cache(&self) -> Option<Arc<dyn ModelClient>> {
+        Some(self.inner.clone())
+    }
+
+    async fn complete(
+        &self,
+        messages: Vec<Message>,
+        max_token: u16,
+    ) -> anyhow::Result<LlamaResponseMessage> {
+        self.complete_with_tools(messages, &[], max_token).await
+    }
+
+    async fn complete_with_tools(
+        &self,
+        messages: Vec<Message>,
+        tools: &[Value],
+        max_token: u16,
+    ) -> anyhow::Result<LlamaResponseMessage> {
+        let key = self.key(&messages, tools, max_token);
+        if let Some(response) = self.lookup(&key) {
+            return Ok(response);
+        }
+        let response = match tools.is_empty() {
+            true => self.inner.complete(messages, max_token).await?,
+            false => {
+                self.inner
+                    .complete_with_tools(messages, tools, max_token)
+                    .await?
+            }
+        };
+        self.store(&key, &response);
+        Ok(response)
+    }
+
+    // A cached answer arrives as one delta.
+    async fn complete_stream(
+        &self,
+        messages: Vec<Message>,
+        max_token: u16,
+        on_delta: &mut OnDelta<'_>,
+    ) -> anyhow::Result<LlamaResponseMessage> {
+        let key = self.key(&messages, &[], max_token);
+        if let Some(response) = self.lookup(&key) {
+            if let Content::Text(text) = &response.content {
+                on_delta(text);
+            }
+            return Ok(response);
+        }
+        let response = self
+            .inner
+            .complete_stream(messages, max_token, on_delta)
+            .await?;
+        self.store(&key, &response);
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conversable_agent::ConversableAgent;
+    use crate::model_client::ScriptedModelClient;
+    use async_openai::types::Role;
+
+    fn question(text: &str) -> Vec<Message> {
+        vec![Message::new(
+            Some(Content::Text(text.to_string())),
+            None,
+            Some(Role::User),
+        )]
+    }
+
+    #[tokio::test]
+    async fn identical_requests_are_answered_from_the_cache() {
+        let dir = std::env::temp_dir().join(format!("response-cache-{}", std::process::id()));
+        let caches: Vec<Arc<dyn ResponseCache>> = vec![
+            Arc::new(SqliteResponseCache::open_in_memory().unwrap()),
+            Arc::new(DirectoryResponseCache::new(&dir).unwrap()),
+        ];
+        for cache in caches {
+            let server = Arc::new(ScriptedModelClient::from_texts(&["first", "second"]));
+            let client = CachedModelClient::new(server.clone(), cache);
+
+            let reply = client.complete(question("hi"), 100).await.unwrap();
+            let again = client.complete(question("hi"), 100).await.unwrap();
+            assert_eq!(again, reply);
+            assert_eq!(server.remaining(), 1);
+
+            let seeded = client.with_seed("run-2");
+            let reply = seeded.complete(question("hi"), 100).await.unwrap();
+            assert_eq!(reply.content, Content::Text("second".to_string()));
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn the_key_tells_endpoints_and_tool_modes_apart() {
+        use crate::llm_llama_local::LlmClientConfig;
+        use crate::model_client::OpenAiCompatibleClient;
+
+        let config = |api_base: &str| LlmClientConfig::new(api_base, "model", "key");
+        let clients = [
+            OpenAiCompatibleClient::new(config("http://localhost:8080/v1")),
+            OpenAiCompatibleClient::new(config("http://gpu-box:8080/v1")),
+            OpenAiCompatibleClient::new(config("http://localhost:8080/v1").with_native_tools()),
+        ];
+        let keys: Vec<String> = clients
+            .iter()
+            .map(|client| cache_key(&CacheTarget::of(client), &question("hi"), &[], 100, None))
+            .collect();
+        assert_ne!(keys[0], keys[1]);
+        assert_ne!(keys[0], keys[2]);
+    }
+
+    #[test]
+    fn the_directory_cache_leaves_no_partial_files() {
+        let dir = std::env::temp_dir().join(format!("response-cache-put-{}", std::process::id()));
+        let cache = DirectoryResponseCache::new(&dir).unwrap();
+        let response = LlamaResponseMessage {
+            content: Content::Text("hi".to_string()),
+            role: Role::Assistant,
+            usage: async_openai::types::CompletionUsage {
+                prompt_tokens: 1,
+                completion_tokens: 1,
+                total_tokens: 2,
+            },
+        };
+        cache.put("key", &response).unwrap();
+        cache.put("key", &response).unwrap();
+
+        let names: Vec<String> = std::fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        assert_eq!(names, vec!["key.json"]);
+        assert_eq!(cache.get("key").unwrap(), Some(response));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn an_agent_can_bypass_a_shared_cache() {
+        let server = Arc::new(ScriptedModelClient::from_texts(&["cached", "fresh"]));
+        let cached: Arc<dyn ModelClient> = Arc::new(CachedModelClient::new(
+            server.clone(),
+            Arc::new(SqliteResponseCache::open_in_memory().unwrap()),
+        ));
+        let mut agent = ConversableAgent::new("assistant");
+        agent.set_model_client(cached);
+
+        agent.chat(question("hi"), 100).await.unwrap();
+        agent.set_cache_bypass(true);
+        let reply = agent.chat(question("hi"), 100).await.unwrap();
+        assert_eq!(reply.content, Content::Text("fresh".to_string()));
+        assert_eq!(server.remaining(), 0);
+    }
+}
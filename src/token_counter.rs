@@ -0,0 +1,283 @@
+use crate::conversable_agent::Message;
+use async_openai::types::Role;
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+
+pub trait TokenCounter: Send + Sync {
+    fn count_tokens(&self, text: &str) -> usize;
+
+    fn count_message_tokens(&self, message: &Message) -> usize {
+        message
+            .content_to_string()
+            .map_or(0, |text| self.count_tokens(&text))
+    }
+}
+
+// Rough, but needs neither a model nor a server; the fallback for every other counter.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct WordCountTokenCounter;
+
+impl TokenCounter for WordCountTokenCounter {
+    fn count_tokens(&self, text: &str) -> usize {
+        text.split_whitespace().count()
+    }
+}
+
+// Past this many texts the counts remembered by LlamaServerTokenCounter start over.
+const MAX_CACHED_COUNTS: usize = 4096;
+
+// Asks the llama.cpp server that runs the model for the exact count through its /tokenize
+// endpoint; when the server cannot be reached the fallback counter is used. The request
+// blocks, so async code has to count on a blocking thread (tokio::task::spawn_blocking), as
+// compress_history and AsyncMessageStore do. Each text is sent once, later counts of it are
+// answered from memory.
+pub struct LlamaServerTokenCounter {
+    pub base_url: String,
+    pub timeout: Duration,
+    pub fallback: Arc<dyn TokenCounter>,
+    // created on the first count, which already runs on a blocking thread
+    client: OnceLock<reqwest::blocking::Client>,
+    // by hash of the text
+    counts: Mutex<HashMap<u64, usize>>,
+}
+
+impl LlamaServerTokenCounter {
+    pub fn new(base_url: &str) -> Self {
+        LlamaServerTokenCounter {
+            base_url: base_url.trim_end_matches('/').to_string(),
+            timeout: Duration::from_secs(5),
+            fallback: Arc::new(WordCountTokenCounter),
+            client: OnceLock::new(),
+            counts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn tokenize(&self, text: &str) -> anyhow::Result<usize> {
+        #[derive(Deserialize)]
+        struct TokenizeResponse {
+            tokens: Vec<serde_json::Value>,
+        }
+
+        let res = self
+            .client
+            .get_or_init(reqwest::blocking::Client::new)
+            .post(format!("{}/tokenize", self.base_url))
+            .timeout(self.timeout)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(json!({ "content": text }).to_string())
+            .send()?
+            .error_for_status()?
+            .text()?;
+        let parsed: TokenizeResponse = serde_json::from_str(&res)?;
+        Ok(parsed.tokens.len())
+    }
+}
+
+impl TokenCounter for LlamaServerTokenCounter {
+    fn count_tokens(&self, text: &str) -> usize {
+        let mut hasher = DefaultHasher::new();
+        text.hash(&mut hasher);
+        let key = hasher.finish();
+        if let Some(count) = self.counts.lock().unwrap().get(&key) {
+            return *count;
+        }
+        match self.tokenize(text) {
+            Ok(count) => {
+                let mut counts = self.counts.lock().unwrap();
+                if counts.len() >= MAX_CACHED_COUNTS {
+                    counts.clear();
+                }
+                counts.insert(key, count);
+                count
+            }
+            Err(e) => {
+                println!("Error counting tokens with {}: {:?}", self.base_url, e);
+                self.fallback.count_tokens(text)
+            }
+        }
+    }
+}
+
+// Keeps the newest entries whose token counts fit into the budget, plus the first system
+// entry no matter what, and returns them in their original order.
+pub fn fit_to_budget<T: Clone>(
+    items: &[T],
+    budget: usize,
+    tokens: impl Fn(&T) -> usize,
+    is_system: impl Fn(&T) -> bool,
+) -> Vec<T> {
+    let system_index = items.iter().position(&is_system);
+    let mut remaining = budget.saturating_sub(system_index.map_or(0, |i| tokens(&items[i])));
+
+    let mut kept: Vec<usize> = Vec::new();
+    for (index, item) in items.iter().enumerate().rev() {
+        if Some(index) == system_index {
+            continue;
+        }
+        let cost = tokens(item);
+        if cost > remaining {
+            break;
+        }
+        remaining -= cost;
+        kept.push(index);
+    }
+    kept.extend(system_index);
+    kept.sort_unstable();
+
+    kept.into_iter().map(|index| items[index].clone()).collect()
+}
+
+pub fn messages_within_budget(
+    messages: &[Message],
+    budget: usize,
+    counter: &dyn TokenCounter,
+) -> Vec<Message> {
+    fit_to_budget(
+        messages,
+        budget,
+        |message| counter.count_message_tokens(message),
+        |message| message.role == Some(Role::System),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llama_structs::Content;
+
+    fn message(role: Role, text: &str) -> Message {
+        Message::new(Some(Content::Text(text.to_string())), None, Some(role))
+    }
+
+    #[test]
+    fn the_newest_items_that_fit_are_kept_in_order() {
+        let items = ["a", "bb", "ccc", "dd"];
+        let kept = fit_to_budget(&items, 6, |item| item.len(), |_| false);
+        assert_eq!(kept, vec!["ccc", "dd"]);
+        // an item over the rest of the budget ends the window, even if older ones would fit
+        let kept = fit_to_budget(&items, 4, |item| item.len(), |_| false);
+        assert_eq!(kept, vec!["dd"]);
+        assert!(fit_to_budget(&items, 1, |item| item.len(), |_| false).is_empty());
+    }
+
+    #[test]
+    fn the_first_system_entry_is_always_kept() {
+        let items = ["system", "a", "bb", "ccc"];
+        let is_system = |item: &&str| *item == "system";
+        let kept = fit_to_budget(&items, 9, |item| item.len(), is_system);
+        assert_eq!(kept, vec!["system", "ccc"]);
+        let kept = fit_to_budget(&items, 0, |item| item.len(), is_system);
+        assert_eq!(kept, vec!["system"]);
+    }
+
+    #[test]
+    fn messages_are_counted_with_the_given_counter() {
+        let messages = vec![
+            message(Role::System, "be brief"),
+            message(Role::User, "one two three"),
+            message(Role::Assistant, "four five"),
+            message(Role::User, "six"),
+        ];
+        let window = messages_within_budget(&messages, 6, &WordCountTokenCounter);
+        assert_eq!(
+            window,
+            vec![
+                messages[0].clone(),
+                messages[2].clone(),
+                messages[3].clone()
+            ]
+        );
+    }
+
+    #[test]
+    fn an_unreachable_server_falls_back_to_the_word_count() {
+        let mut counter = LlamaServerTokenCounter::new("http://127.0.0.1:9/");
+        counter.timeout = Duration::from_millis(500);
+        assert_eq!(counter.count_tokens("three little words"), 3);
+    }
+
+    // A /tokenize endpoint that answers every text with two tokens and counts its requests.
+    fn tokenize_server() -> (String, Arc<std::sync::atomic::AtomicUsize>) {
+        use std::io::{BufRead, BufReader, Read, Write};
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        let hits = Arc::new(AtomicUsize::new(0));
+        let counted = hits.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line.trim().is_empty() {
+                        break;
+                    }
+                    if let Some(value) = line.to_lowercase().strip_prefix("content-length:") {
+                        length = value.trim().parse().unwrap();
+                    }
+                }
+                reader.read_exact(&mut vec![0; length]).unwrap();
+                counted.fetch_add(1, Ordering::SeqCst);
+                let body = r#"{"tokens": [1, 2]}"#;
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                );
+                stream.write_all(response.as_bytes()).unwrap();
+            }
+        });
+        (base_url, hits)
+    }
+
+    #[test]
+    fn each_text_is_sent_to_the_server_once() {
+        let (base_url, hits) = tokenize_server();
+        let counter = LlamaServerTokenCounter::new(&base_url);
+        assert_eq!(counter.count_tokens("hello there"), 2);
+        assert_eq!(counter.count_tokens("hello there"), 2);
+        assert_eq!(counter.count_tokens("something else"), 2);
+        assert_eq!(hits.load(std::sync::atomic::Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn stores_count_with_the_server_off_the_runtime_threads() {
+        use crate::llama_structs::Content;
+        use crate::message_store::{AsyncMessageStore, MessageStore, SqliteMessageStore};
+
+        let (base_url, hits) = tokenize_server();
+        let store = Arc::new(
+            SqliteMessageStore::open_in_memory()
+                .unwrap()
+                .with_token_counter(Arc::new(LlamaServerTokenCounter::new(&base_url))),
+        );
+        let text = |t: &str| Message::new(Some(Content::Text(t.to_string())), None, None);
+
+        // saved right on the runtime thread: the words are counted, nothing blocks
+        store
+            .save_message("c1", "a".to_string(), text("one two three"), String::new())
+            .unwrap();
+        AsyncMessageStore::new(store.clone())
+            .save_message("c1", "a".to_string(), text("four five six"), String::new())
+            .await
+            .unwrap();
+
+        let counts: Vec<i64> = store
+            .load_conversation("c1")
+            .unwrap()
+            .iter()
+            .map(|stored| stored.tokens_count)
+            .collect();
+        assert_eq!(counts, vec![3, 2]);
+        assert_eq!(hits.load(std::sync::atomic::Ordering::SeqCst), 1);
+    }
+}
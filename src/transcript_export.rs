@@ -0,0 +1,345 @@
+use crate::conversable_agent::Message;
+use crate::llama_structs::Content;
+use crate::message_store::MessageStore;
+use async_openai::types::Role;
+use regex::Regex;
+use serde_json::{json, Value};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TranscriptFormat {
+    Markdown,
+    Json,
+    Html,
+}
+
+enum Segment<'a> {
+    Prose(&'a str),
+    Code(Option<&'a str>, &'a str),
+}
+
+fn split_code_blocks(text: &str) -> Vec<Segment<'_>> {
+    let code_block = Regex::new(r"(?s)```[ \t]*(\w+)?[ \t]*\r?\n(.*?)\r?\n?[ \t]*```").unwrap();
+    let mut segments = Vec::new();
+    let mut last = 0;
+
+    for cap in code_block.captures_iter(text) {
+        let whole = cap.get(0).unwrap();
+        if whole.start() > last {
+            segments.push(Segment::Prose(&text[last..whole.start()]));
+        }
+        segments.push(Segment::Code(
+            cap.get(1).map(|m| m.as_str()),
+            cap.get(2).map_or("", |m| m.as_str()),
+        ));
+        last = whole.end();
+    }
+    if last < text.len() {
+        segments.push(Segment::Prose(&text[last..]));
+    }
+    segments
+}
+
+fn role_label(role: &Option<Role>) -> &'static str {
+    match role {
+        Some(Role::System) => "system",
+        Some(Role::User) => "user",
+        Some(Role::Assistant) => "assistant",
+        Some(Role::Tool) => "tool",
+        Some(Role::Function) => "function",
+        None => "unknown",
+    }
+}
+
+// Tool and function messages carry the output of a tool call or a code execution.
+fn is_execution_result(message: &Message) -> bool {
+    matches!(message.role, Some(Role::Tool) | Some(Role::Function))
+}
+
+fn speaker(message: &Message) -> String {
+    message
+        .name
+        .clone()
+        .unwrap_or_else(|| role_label(&message.role).to_string())
+}
+
+fn arguments_json(arguments: &Option<std::collections::HashMap<String, String>>) -> String {
+    let mut sorted: Vec<(&String, &String)> = arguments.iter().flatten().collect();
+    sorted.sort();
+    let map: serde_json::Map<String, Value> = sorted
+        .into_iter()
+        .map(|(k, v)| (k.clone(), Value::String(v.clone())))
+        .collect();
+    serde_json::to_string_pretty(&map).unwrap_or_default()
+}
+
+// A code fence longer than any run of backticks inside the content, so the content cannot
+// close it early.
+fn fence_for(content: &str) -> String {
+    let longest = content
+        .split(|c: char| c != '`')
+        .map(str::len)
+        .max()
+        .unwrap_or(0);
+    "`".repeat(longest.max(2) + 1)
+}
+
+fn fenced(language: &str, content: &str) -> String {
+    let fence = fence_for(content);
+    format!("{}{}\n{}\n{}\n", fence, language, content, fence)
+}
+
+pub fn to_markdown(messages: &[Message]) -> String {
+    let mut out = String::from("# Conversation transcript\n");
+
+    for (index, message) in messages.iter().enumerate() {
+        out.push_str(&format!(
+            "\n## {}. {} ({})\n\n",
+            index + 1,
+            speaker(message),
+            role_label(&message.role)
+        ));
+
+        match &message.content {
+            Some(Content::ToolCall(tool_call)) => {
+                out.push_str(&format!("**Tool call:** `{}`\n\n", tool_call.name));
+                out.push_str(&fenced("json", &arguments_json(&tool_call.arguments)));
+            }
+            Some(Content::Text(text)) if is_execution_result(message) => {
+                out.push_str("**Result:**\n\n");
+                out.push_str(&fenced("text", text.trim_end()));
+            }
+            Some(Content::Text(text)) => {
+                out.push_str(text.trim_end());
+                out.push('\n');
+            }
+            None => out.push_str("_(empty)_\n"),
+        }
+    }
+    out
+}
+
+pub fn to_json(messages: &[Message]) -> String {
+    let entries: Vec<Value> = messages
+        .iter()
+        .enumerate()
+        .map(|(index, message)| {
+            let mut entry = json!({
+                "index": index,
+                "name": message.name,
+                "role": role_label(&message.role),
+            });
+            match &message.content {
+                Some(Content::ToolCall(tool_call)) => {
+                    entry["kind"] = json!("tool_call");
+                    entry["tool_call"] = json!({
+                        "name": tool_call.name,
+                        "arguments": tool_call.arguments,
+                    });
+                }
+                Some(Content::Text(text)) => {
+                    entry["kind"] = if is_execution_result(message) {
+                        json!("execution_result")
+                    } else {
+                        json!("text")
+                    };
+                    entry["content"] = json!(text);
+                    let code_blocks: Vec<Value> = split_code_blocks(text)
+                        .into_iter()
+                        .filter_map(|segment| match segment {
+                            Segment::Code(language, code) => {
+                                Some(json!({ "language": language, "code": code }))
+                            }
+                            Segment::Prose(_) => None,
+                        })
+                        .collect();
+                    if !code_blocks.is_empty() {
+                        entry["code_blocks"] = json!(code_blocks);
+                    }
+                }
+                None => entry["kind"] = json!("empty"),
+            }
+            entry
+        })
+        .collect();
+
+    serde_json::to_string_pretty(&json!({ "messages": entries })).unwrap_or_default()
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+fn text_to_html(text: &str) -> String {
+    split_code_blocks(text)
+        .into_iter()
+        .map(|segment| match segment {
+            Segment::Code(language, code) => format!(
+                "<pre class=\"code\"><span class=\"lang\">{}</span><code>{}</code></pre>",
+                escape_html(language.unwrap_or("code")),
+                escape_html(code)
+            ),
+            Segment::Prose(prose) => prose
+                .split("\n\n")
+                .map(str::trim)
+                .filter(|paragraph| !paragraph.is_empty())
+                .map(|paragraph| format!("<p>{}</p>", escape_html(paragraph).replace('\n', "<br>")))
+                .collect::<Vec<String>>()
+                .join("\n"),
+        })
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+const HTML_STYLE: &str = "body{font-family:-apple-system,Segoe UI,Helvetica,Arial,sans-serif;max-width:60em;margin:2em auto;padding:0 1em;color:#1f2328;background:#fff}
+.message{border:1px solid #d0d7de;border-radius:6px;margin:1em 0;padding:.5em 1em}
+.message header{font-weight:600;margin-bottom:.5em}
+.message header .role{font-weight:400;color:#656d76;margin-left:.5em}
+.system{background:#f6f8fa}.assistant{border-left:4px solid #0969da}.user{border-left:4px solid #1a7f37}
+.tool,.function{border-left:4px solid #9a6700}
+pre{background:#f6f8fa;border-radius:6px;padding:.75em;overflow-x:auto;white-space:pre-wrap}
+pre .lang{display:block;font-size:.75em;color:#656d76;margin-bottom:.25em}
+.tool-call pre,pre.result{background:#fff8c5}
+.label{font-weight:600}";
+
+pub fn to_html(messages: &[Message]) -> String {
+    let body = messages
+        .iter()
+        .enumerate()
+        .map(|(index, message)| {
+            let role = role_label(&message.role);
+            let content = match &message.content {
+                Some(Content::ToolCall(tool_call)) => format!(
+                    "<div class=\"tool-call\"><span class=\"label\">Tool call:</span> <code>{}</code><pre>{}</pre></div>",
+                    escape_html(&tool_call.name),
+                    escape_html(&arguments_json(&tool_call.arguments))
+                ),
+                Some(Content::Text(text)) if is_execution_result(message) => format!(
+                    "<span class=\"label\">Result:</span><pre class=\"result\">{}</pre>",
+                    escape_html(text.trim_end())
+                ),
+                Some(Content::Text(text)) => text_to_html(text),
+                None => "<p><em>(empty)</em></p>".to_string(),
+            };
+            format!(
+                "<section class=\"message {}\">\n<header>{}. {}<span class=\"role\">{}</span></header>\n{}\n</section>",
+                role,
+                index + 1,
+                escape_html(&speaker(message)),
+                role,
+                content
+            )
+        })
+        .collect::<Vec<String>>()
+        .join("\n");
+
+    format!(
+        "<!DOCTYPE html>\n<html lang=\"en\">\n<head>\n<meta charset=\"utf-8\">\n<title>Conversation transcript</title>\n<style>\n{}\n</style>\n</head>\n<body>\n<h1>Conversation transcript</h1>\n{}\n</body>\n</html>\n",
+        HTML_STYLE, body
+    )
+}
+
+pub fn export_transcript(messages: &[Message], format: TranscriptFormat) -> String {
+    match format {
+        TranscriptFormat::Markdown => to_markdown(messages),
+        TranscriptFormat::Json => to_json(messages),
+        TranscriptFormat::Html => to_html(messages),
+    }
+}
+
+pub fn export_from_store(
+    store: &dyn MessageStore,
+    conversation_id: &str,
+    format: TranscriptFormat,
+) -> anyhow::Result<String> {
+    // messages posted to only some agents of a group chat stay out of the transcript
+    let messages: Vec<Message> = store
+        .load_conversation(conversation_id)?
+        .into_iter()
+        .filter(|stored| stored.is_public())
+        .map(|stored| stored.message)
+        .collect();
+    Ok(export_transcript(&messages, format))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llama_structs::ToolCall;
+    use std::collections::HashMap;
+
+    fn message(text: &str, name: Option<&str>, role: Role) -> Message {
+        Message::new(
+            Some(Content::Text(text.to_string())),
+            name.map(String::from),
+            Some(role),
+        )
+    }
+
+    fn transcript() -> Vec<Message> {
+        vec![
+            message("Print <b>hi</b> & exit", None, Role::User),
+            message(
+                "Sure:\n```python\nprint(\"<b>hi</b>\")\n```",
+                Some("coder"),
+                Role::Assistant,
+            ),
+            Message::new(
+                Some(Content::ToolCall(ToolCall {
+                    name: "run".to_string(),
+                    arguments: Some(HashMap::from([("code".to_string(), "1".to_string())])),
+                    id: None,
+                })),
+                Some("coder".to_string()),
+                Some(Role::Assistant),
+            ),
+            message("<b>hi</b>\n```", Some("run"), Role::Tool),
+        ]
+    }
+
+    #[test]
+    fn markdown_fences_outgrow_backticks_in_the_content() {
+        assert_eq!(fence_for("plain"), "```");
+        assert_eq!(fence_for("a ``` b"), "````");
+        assert_eq!(fence_for("`````"), "``````");
+
+        let markdown = to_markdown(&transcript());
+        assert!(markdown.starts_with("# Conversation transcript\n"));
+        assert!(markdown.contains("\n## 2. coder (assistant)\n\nSure:\n```python\n"));
+        assert!(
+            markdown.contains("**Tool call:** `run`\n\n```json\n{\n  \"code\": \"1\"\n}\n```\n")
+        );
+        assert!(markdown.contains("**Result:**\n\n````text\n<b>hi</b>\n```\n````\n"));
+    }
+
+    #[test]
+    fn json_keeps_kinds_and_code_blocks() {
+        let exported: Value = serde_json::from_str(&to_json(&transcript())).unwrap();
+        let messages = exported["messages"].as_array().unwrap();
+        assert_eq!(messages.len(), 4);
+        assert_eq!(messages[0]["kind"], "text");
+        assert_eq!(messages[0]["name"], Value::Null);
+        assert_eq!(messages[1]["code_blocks"][0]["language"], "python");
+        assert_eq!(
+            messages[1]["code_blocks"][0]["code"],
+            "print(\"<b>hi</b>\")"
+        );
+        assert_eq!(messages[2]["kind"], "tool_call");
+        assert_eq!(messages[2]["tool_call"]["arguments"]["code"], "1");
+        assert_eq!(messages[3]["kind"], "execution_result");
+        assert_eq!(messages[3]["role"], "tool");
+    }
+
+    #[test]
+    fn html_escapes_every_piece_of_content() {
+        let html = to_html(&transcript());
+        assert!(!html.contains("<b>"));
+        assert!(html.contains("<p>Print &lt;b&gt;hi&lt;/b&gt; &amp; exit</p>"));
+        assert!(html.contains("<code>print(&quot;&lt;b&gt;hi&lt;/b&gt;&quot;)</code>"));
+        assert!(html.contains("<pre class=\"result\">&lt;b&gt;hi&lt;/b&gt;\n```</pre>"));
+        assert!(html.contains("<section class=\"message tool\">"));
+    }
+}
@@ -0,0 +1,485 @@
+use crate::conversable_agent::Message;
+use crate::llama_structs::Content;
+use crate::llm_llama_local::LocalServiceProviderConfig;
+use crate::message_store::{now_timestamp, SqliteMessageStore};
+use crate::RECALLED_MEMORY_TEMPLATE;
+use async_openai::types::{CreateEmbeddingRequestArgs, EmbeddingInput, Role};
+use async_openai::Client as OpenAIClient;
+use async_trait::async_trait;
+use rusqlite::{params, Connection};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+#[async_trait]
+pub trait Embedder: Send + Sync {
+    fn model(&self) -> String;
+
+    async fn embed(&self, inputs: Vec<String>) -> anyhow::Result<Vec<Vec<f32>>>;
+}
+
+// Any server speaking the OpenAI /v1/embeddings protocol: llama.cpp started with
+// --embedding, a hosted API, or a stub in tests.
+pub struct OpenAiCompatibleEmbedder {
+    pub config: LocalServiceProviderConfig,
+    pub model: String,
+}
+
+impl OpenAiCompatibleEmbedder {
+    pub fn new(api_base: &str, model: &str, api_key: &str) -> Self {
+        OpenAiCompatibleEmbedder {
+            config: LocalServiceProviderConfig::new(api_base, api_key),
+            model: model.to_string(),
+        }
+    }
+}
+
+#[async_trait]
+impl Embedder for OpenAiCompatibleEmbedder {
+    fn model(&self) -> String {
+        self.model.clone()
+    }
+
+    async fn embed(&self, inputs: Vec<String>) -> anyhow::Result<Vec<Vec<f32>>> {
+        let expected = inputs.len();
+        let client = OpenAIClient::with_config(self.config.clone());
+        let request = CreateEmbeddingRequestArgs::default()
+            .model(self.model.clone())
+            .input(EmbeddingInput::StringArray(inputs))
+            .build()?;
+
+        let mut data = client.embeddings().create(request).await?.data;
+        if data.len() != expected {
+            return Err(anyhow::anyhow!(
+                "asked for {} embeddings, got {}",
+                expected,
+                data.len()
+            ));
+        }
+        data.sort_by_key(|embedding| embedding.index);
+        Ok(data
+            .into_iter()
+            .map(|embedding| embedding.embedding)
+            .collect())
+    }
+}
+
+pub fn vector_to_blob(vector: &[f32]) -> Vec<u8> {
+    vector
+        .iter()
+        .flat_map(|value| value.to_le_bytes())
+        .collect()
+}
+
+pub fn blob_to_vector(blob: &[u8]) -> Vec<f32> {
+    blob.chunks_exact(4)
+        .map(|chunk| f32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]))
+        .collect()
+}
+
+pub fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    if a.len() != b.len() || a.is_empty() {
+        return 0.0;
+    }
+    let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norm_a = a.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let norm_b = b.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm_a == 0.0 || norm_b == 0.0 {
+        return 0.0;
+    }
+    dot / (norm_a * norm_b)
+}
+
+// What gets embedded for a stored message: the text, or a tool call's name and arguments.
+fn memory_text(content: Option<String>, arguments: Option<String>) -> String {
+    match arguments {
+        Some(arguments) => format!("{} {}", content.unwrap_or_default(), arguments),
+        None => content.unwrap_or_default(),
+    }
+}
+
+// Whether `message` is the one stored as `stored`; tool call arguments are compared as a
+// map since their JSON key order is not fixed.
+fn shows(message: &Message, stored: &str) -> bool {
+    match &message.content {
+        Some(Content::Text(text)) => !text.trim().is_empty() && text == stored,
+        Some(Content::ToolCall(call)) => {
+            let (name, arguments) = stored.split_once(' ').unwrap_or((stored, ""));
+            name == call.name
+                && serde_json::from_str::<HashMap<String, String>>(arguments).ok() == call.arguments
+        }
+        None => false,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MemoryHit {
+    pub embedding_id: i64,
+    pub message_id: Option<i64>,
+    pub conversation_id: Option<String>,
+    pub content: String,
+    pub score: f32,
+}
+
+// Semantic recall over the SQLite message store: vectors live in the Embeddings table of
+// the same database and are ranked by cosine similarity to the embedded query.
+pub struct VectorMemory {
+    pub store: Arc<SqliteMessageStore>,
+    pub embedder: Arc<dyn Embedder>,
+}
+
+impl VectorMemory {
+    pub fn new(store: Arc<SqliteMessageStore>, embedder: Arc<dyn Embedder>) -> Self {
+        VectorMemory { store, embedder }
+    }
+
+    // SQLite calls block, so they go to a blocking thread the way AsyncMessageStore does it.
+    async fn run<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&Connection) -> rusqlite::Result<T> + Send + 'static,
+    ) -> anyhow::Result<T> {
+        let store = self.store.clone();
+        tokio::task::spawn_blocking(move || store.with_connection(f)).await?
+    }
+
+    async fn insert(
+        &self,
+        message_id: Option<i64>,
+        conversation_id: Option<String>,
+        content: String,
+        vector: Vec<f32>,
+    ) -> anyhow::Result<i64> {
+        let model = self.embedder.model();
+        self.run(move |conn| {
+            conn.execute(
+                "INSERT INTO Embeddings (message_id, conversation_id, content, model, dimensions, vector, created_at)
+                    VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                params![
+                    message_id,
+                    conversation_id,
+                    content,
+                    model,
+                    vector.len() as i64,
+                    vector_to_blob(&vector),
+                    now_timestamp()
+                ],
+            )?;
+            Ok(conn.last_insert_rowid())
+        })
+        .await
+    }
+
+    pub async fn remember_document(
+        &self,
+        conversation_id: Option<&str>,
+        text: &str,
+    ) -> anyhow::Result<i64> {
+        let vector = self
+            .embedder
+            .embed(vec![text.to_string()])
+            .await?
+            .pop()
+            .ok_or_else(|| anyhow::anyhow!("no embedding returned"))?;
+        self.insert(
+            None,
+            conversation_id.map(String::from),
+            text.to_string(),
+            vector,
+        )
+        .await
+    }
+
+    // Embeds every message of the conversation that has no vector yet; returns how many.
+    pub async fn remember_conversation(&self, conversation_id: &str) -> anyhow::Result<usize> {
+        let conversation = conversation_id.to_string();
+        let pending: Vec<(i64, String)> = self
+            .run(move |conn| {
+                let mut stmt = conn.prepare(
+                "SELECT m.id, m.agent_name, m.message_content, m.tool_arguments FROM GroupChat m
+                WHERE m.conversation_id = ?1 AND m.visibility IS NULL
+                    AND NOT EXISTS (SELECT 1 FROM Embeddings e WHERE e.message_id = m.id)
+                ORDER BY m.seq",
+            )?;
+                let rows = stmt.query_map(params![conversation], |row| {
+                    let agent_name: String = row.get(1)?;
+                    let content: Option<String> = row.get(2)?;
+                    let arguments: Option<String> = row.get(3)?;
+                    Ok((row.get(0)?, agent_name, memory_text(content, arguments)))
+                })?;
+                // messages without any text have nothing to embed
+                rows.filter_map(|row| match row {
+                    Ok((id, agent_name, text)) if !text.trim().is_empty() => {
+                        Some(Ok((id, format!("{}: {}", agent_name, text))))
+                    }
+                    Ok(_) => None,
+                    Err(e) => Some(Err(e)),
+                })
+                .collect()
+            })
+            .await?;
+        if pending.is_empty() {
+            return Ok(0);
+        }
+
+        let vectors = self
+            .embedder
+            .embed(pending.iter().map(|(_, text)| text.clone()).collect())
+            .await?;
+        let count = pending.len();
+        for ((message_id, text), vector) in pending.into_iter().zip(vectors) {
+            self.insert(
+                Some(message_id),
+                Some(conversation_id.to_string()),
+                text,
+                vector,
+            )
+            .await?;
+        }
+        Ok(count)
+    }
+
+    pub async fn recall(
+        &self,
+        query: &str,
+        k: usize,
+        conversation_id: Option<&str>,
+    ) -> anyhow::Result<Vec<MemoryHit>> {
+        let query_vector = self
+            .embedder
+            .embed(vec![query.to_string()])
+            .await?
+            .pop()
+            .ok_or_else(|| anyhow::anyhow!("no embedding returned"))?;
+        let model = self.embedder.model();
+
+        let conversation_id = conversation_id.map(String::from);
+        let mut hits: Vec<MemoryHit> = self
+            .run(move |conn| {
+                let mut stmt = conn.prepare(
+                    "SELECT id, message_id, conversation_id, content, vector FROM Embeddings
+                WHERE model = ?1 AND (?2 IS NULL OR conversation_id = ?2)",
+                )?;
+                let rows = stmt.query_map(params![model, conversation_id], |row| {
+                    let vector = blob_to_vector(&row.get::<_, Vec<u8>>(4)?);
+                    Ok(MemoryHit {
+                        embedding_id: row.get(0)?,
+                        message_id: row.get(1)?,
+                        conversation_id: row.get(2)?,
+                        content: row.get(3)?,
+                        score: cosine_similarity(&query_vector, &vector),
+                    })
+                })?;
+                rows.collect()
+            })
+            .await?;
+
+        hits.sort_by(|a, b| {
+            b.score
+                .partial_cmp(&a.score)
+                .unwrap_or(std::cmp::Ordering::Equal)
+        });
+        hits.truncate(k);
+        Ok(hits)
+    }
+
+    // A system note with the best matches that are not already part of `visible`, for an
+    // agent whose context window no longer holds the older turns.
+    pub async fn recall_note(
+        &self,
+        conversation_id: &str,
+        visible: &[Message],
+        k: usize,
+    ) -> anyhow::Result<Option<Message>> {
+        let Some(query) = visible
+            .iter()
+            .rev()
+            .find(|message| message.role != Some(Role::System))
+            .and_then(|message| message.content_to_string())
+        else {
+            return Ok(None);
+        };
+
+        self.remember_conversation(conversation_id).await?;
+        // stored contents read "{agent}: {text}"; a hit is already in view when its text is
+        // exactly that of a visible message
+        let hits: Vec<MemoryHit> = self
+            .recall(&query, k + visible.len(), Some(conversation_id))
+            .await?
+            .into_iter()
+            .filter(|hit| {
+                let stored = hit.content.split_once(": ").map_or("", |(_, text)| text);
+                !visible.iter().any(|message| shows(message, stored))
+            })
+            .take(k)
+            .collect();
+        if hits.is_empty() {
+            return Ok(None);
+        }
+
+        let recalled = hits
+            .iter()
+            .map(|hit| format!("- {}", hit.content))
+            .collect::<Vec<String>>()
+            .join("\n");
+        let note = {
+            let template = RECALLED_MEMORY_TEMPLATE.lock().unwrap();
+            template(&[&recalled])
+        };
+        Ok(Some(Message::new(
+            Some(Content::Text(note)),
+            None,
+            Some(Role::System),
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message_store::MessageStore;
+
+    // Deterministic stand-in for an embeddings server: counts the letters a-z.
+    struct LetterEmbedder;
+
+    #[async_trait]
+    impl Embedder for LetterEmbedder {
+        fn model(&self) -> String {
+            "letters".to_string()
+        }
+
+        async fn embed(&self, inputs: Vec<String>) -> anyhow::Result<Vec<Vec<f32>>> {
+            Ok(inputs
+                .iter()
+                .map(|input| {
+                    let mut vector = vec![0.0; 26];
+                    for c in input
+                        .to_lowercase()
+                        .chars()
+                        .filter(|c| c.is_ascii_lowercase())
+                    {
+                        vector[(c as u8 - b'a') as usize] += 1.0;
+                    }
+                    vector
+                })
+                .collect())
+        }
+    }
+
+    fn text(content: &str) -> Message {
+        Message::new(
+            Some(Content::Text(content.to_string())),
+            None,
+            Some(Role::User),
+        )
+    }
+
+    #[tokio::test]
+    async fn recall_ranks_by_similarity_and_follows_deletes() {
+        let store = Arc::new(SqliteMessageStore::open_in_memory().unwrap());
+        let conversation = store.create_conversation(None, None).unwrap();
+        for content in ["zzz zzz zzz", "apple banana", "quick brown fox", " "] {
+            store
+                .save_message(
+                    &conversation,
+                    "user".to_string(),
+                    text(content),
+                    "a".to_string(),
+                )
+                .unwrap();
+        }
+        let memory = VectorMemory::new(store.clone(), Arc::new(LetterEmbedder));
+
+        // the blank message is skipped
+        assert_eq!(
+            memory.remember_conversation(&conversation).await.unwrap(),
+            3
+        );
+        assert_eq!(
+            memory.remember_conversation(&conversation).await.unwrap(),
+            0
+        );
+
+        let hits = memory.recall("banana apple", 2, None).await.unwrap();
+        assert_eq!(hits.len(), 2);
+        assert_eq!(hits[0].content, "user: apple banana");
+        assert!(hits[0].score > hits[1].score);
+
+        store.delete_conversation(&conversation).unwrap();
+        assert!(memory.recall("banana", 5, None).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn the_note_leaves_out_only_what_is_exactly_in_view() {
+        let store = Arc::new(SqliteMessageStore::open_in_memory().unwrap());
+        let conversation = store.create_conversation(None, None).unwrap();
+        for content in ["apple banana", "green apple"] {
+            store
+                .save_message(
+                    &conversation,
+                    "user".to_string(),
+                    text(content),
+                    "a".to_string(),
+                )
+                .unwrap();
+        }
+        let memory = VectorMemory::new(store, Arc::new(LetterEmbedder));
+
+        // an empty turn hides nothing, "apple" only ends a stored text and hides nothing either
+        let visible = vec![text(""), text("green apple"), text("apple")];
+        let note = memory
+            .recall_note(&conversation, &visible, 3)
+            .await
+            .unwrap()
+            .unwrap()
+            .content_to_string()
+            .unwrap();
+        assert!(note.contains("- user: apple banana"));
+        assert!(!note.contains("green apple"));
+    }
+
+    #[tokio::test]
+    async fn an_agent_in_a_group_chat_recalls_what_the_chat_stored() {
+        use crate::conversable_agent::ConversableAgent;
+        use crate::groupchat::GroupChat;
+        use crate::llama_structs::ToolCall;
+        use crate::model_client::ScriptedModelClient;
+        use std::collections::HashMap;
+
+        let store = Arc::new(SqliteMessageStore::open_in_memory().unwrap());
+        let mut group = GroupChat::new();
+        group.set_store(store.clone());
+        let client = Arc::new(ScriptedModelClient::from_texts(&["Kiwi."]));
+        let mut critic = ConversableAgent::new("critic");
+        critic.set_model_client(client.clone());
+        critic.set_memory(Arc::new(VectorMemory::new(store, Arc::new(LetterEmbedder))));
+        group.register(&critic);
+
+        let weigh = Message::new(
+            Some(Content::ToolCall(ToolCall {
+                name: "weigh".to_string(),
+                arguments: Some(HashMap::from([
+                    ("fruit".to_string(), "kiwi".to_string()),
+                    ("unit".to_string(), "gram".to_string()),
+                ])),
+                id: None,
+            })),
+            Some("writer".to_string()),
+            Some(Role::Assistant),
+        );
+        group.post(text("apple banana pie")).await;
+        group.post(weigh.clone()).await;
+
+        // only the tool call is still in view; the pie is recalled from the chat's history
+        let critic = group.agents["critic"].clone();
+        critic
+            .a_generate_reply(vec![weigh, text("which fruit pie?")], None)
+            .await
+            .unwrap();
+        let note = client.requests()[0]
+            .iter()
+            .find(|message| message.role == Some(Role::System))
+            .and_then(|message| message.content_to_string())
+            .unwrap();
+        assert!(note.contains("- user: apple banana pie"));
+        assert!(!note.contains("weigh"));
+    }
+}